@@ -8,10 +8,13 @@ CONFIG_PATH=/data/options.json
 
 # Read configuration from Home Assistant addon options using jq
 export HA_TUNNEL_SERVER="$(bashio::config 'server')"
+export HA_TUNNEL_SERVERS="$(bashio::config 'backup_servers' | paste -sd, -)"
+export HA_TUNNEL_SERVER_MODE="$(bashio::config 'server_mode')"
 export HA_TUNNEL_SECRET="$(bashio::config 'secret')"
 export HA_TUNNEL_HA_SERVER="DETECT"
 export HA_TUNNEL_ASSISTANT_ALEXA="$(bashio::config 'assistant_alexa')"
 export HA_TUNNEL_ASSISTANT_GOOGLE="$(bashio::config 'assistant_google')"
+export HA_TUNNEL_AUTH_PROXY="$(bashio::config 'auth_proxy')"
 export HA_TUNNEL_RECONNECT_INTERVAL="$(bashio::config 'reconnect_interval')"
 export HA_TUNNEL_HEARTBEAT_INTERVAL="$(bashio::config 'heartbeat_interval')"
 export HA_TUNNEL_HA_TIMEOUT="$(bashio::config 'ha_timeout')"
@@ -25,7 +28,7 @@ if [ -n "$HA_EXTERNAL_URL" ]; then
 fi
 
 echo "Starting HA Tunnel Client..."
-echo "Server: ${HA_TUNNEL_SERVER}"
+echo "Server: ${HA_TUNNEL_SERVER} ${HA_TUNNEL_SERVERS} (${HA_TUNNEL_SERVER_MODE})"
 echo "Alexa: ${HA_TUNNEL_ASSISTANT_ALEXA}, Google: ${HA_TUNNEL_ASSISTANT_GOOGLE}"
 
 # Run the tunnel client
@@ -0,0 +1,236 @@
+use common::error::ProxyError;
+use url::Url;
+use url::form_urlencoded;
+
+/// Client ID and redirect hosts used by the official Alexa and Google
+/// Assistant account linking
+pub const DEFAULT_REDIRECT_HOSTS: [&str; 5] = [
+    "pitangui.amazon.com",
+    "layla.amazon.com",
+    "alexa.amazon.co.jp",
+    "oauth-redirect.googleusercontent.com",
+    "oauth-redirect-sandbox.googleusercontent.com",
+];
+
+/// Path prefixes of the Home Assistant login page and its assets, only
+/// forwarded when the auth flow is proxied through the tunnel
+const AUTH_FLOW_PREFIXES: [&str; 4] = [
+    "/auth/login_flow",
+    "/frontend_latest/",
+    "/frontend_es5/",
+    "/static/",
+];
+
+/// Returns true if the request is part of the Home Assistant login flow
+/// (besides `/auth/authorize` and `/auth/token` themselves)
+pub fn is_auth_flow_request(method: &str, path: &str) -> bool {
+    match method {
+        "GET" => {
+            path == "/auth/providers"
+                || path == "/manifest.json"
+                || AUTH_FLOW_PREFIXES.iter().any(|p| path.starts_with(p))
+        }
+        "POST" | "DELETE" => path.starts_with("/auth/login_flow"),
+        _ => false,
+    }
+}
+
+/// Returns the first value of a query string parameter
+pub fn query_param(query: Option<&str>, name: &str) -> Option<String> {
+    form_urlencoded::parse(query?.as_bytes())
+        .find(|(key, _)| key == name)
+        .map(|(_, value)| value.into_owned())
+}
+
+/// Checks that an OAuth URL parameter is an https URL pointing at one of the
+/// allowed hosts
+fn check_allowed_host(name: &str, value: &str, allowed_hosts: &[String]) -> Result<(), ProxyError> {
+    let url =
+        Url::parse(value).map_err(|_| ProxyError::InvalidRequest(format!("Invalid {}", name)))?;
+
+    match url.host_str() {
+        Some(host)
+            if url.scheme() == "https"
+                && allowed_hosts.iter().any(|h| h.eq_ignore_ascii_case(host)) =>
+        {
+            Ok(())
+        }
+        _ => Err(ProxyError::InvalidRequest(format!(
+            "{} {} is not allowed",
+            name, value
+        ))),
+    }
+}
+
+/// Checks that `client_id` and `redirect_uri` belong to one of the allowed
+/// assistants (e.g. `https://pitangui.amazon.com/` and
+/// `https://pitangui.amazon.com/api/skill/link/...`)
+pub fn validate_oauth_client(
+    client_id: Option<&str>,
+    redirect_uri: Option<&str>,
+    allowed_hosts: &[String],
+) -> Result<(), ProxyError> {
+    let client_id =
+        client_id.ok_or_else(|| ProxyError::InvalidRequest("Missing client_id".to_string()))?;
+    let redirect_uri = redirect_uri
+        .ok_or_else(|| ProxyError::InvalidRequest("Missing redirect_uri".to_string()))?;
+
+    check_allowed_host("client_id", client_id, allowed_hosts)?;
+    check_allowed_host("redirect_uri", redirect_uri, allowed_hosts)
+}
+
+/// Validates the OAuth parameters in a login flow creation request body
+/// (`{"client_id": ..., "handler": [...], "redirect_uri": ...}`)
+pub fn validate_login_flow_body(
+    body: Option<&[u8]>,
+    allowed_hosts: &[String],
+) -> Result<(), ProxyError> {
+    let body = body.ok_or_else(|| ProxyError::InvalidRequest("Missing body".to_string()))?;
+    let json: serde_json::Value = serde_json::from_slice(body)
+        .map_err(|_| ProxyError::InvalidRequest("Body is not valid JSON".to_string()))?;
+
+    validate_oauth_client(
+        json.get("client_id").and_then(|v| v.as_str()),
+        json.get("redirect_uri").and_then(|v| v.as_str()),
+        allowed_hosts,
+    )
+}
+
+/// Only lets form-encoded token requests with a grant type used by account
+/// linking (or a revocation) through to Home Assistant
+pub fn validate_token_request(
+    headers: &[(String, String)],
+    body: Option<&[u8]>,
+) -> Result<(), ProxyError> {
+    let is_form = headers.iter().any(|(name, value)| {
+        name.eq_ignore_ascii_case("content-type")
+            && value.starts_with("application/x-www-form-urlencoded")
+    });
+    if !is_form {
+        return Err(ProxyError::InvalidRequest(
+            "Token request must be form encoded".to_string(),
+        ));
+    }
+
+    let body = body.unwrap_or_default();
+    let mut params = form_urlencoded::parse(body);
+    let valid = params.any(|(key, value)| {
+        (key == "grant_type" && matches!(value.as_ref(), "authorization_code" | "refresh_token"))
+            || (key == "action" && value == "revoke")
+    });
+    if !valid {
+        return Err(ProxyError::InvalidRequest(
+            "Unsupported token grant type".to_string(),
+        ));
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn hosts() -> Vec<String> {
+        DEFAULT_REDIRECT_HOSTS
+            .iter()
+            .map(|h| h.to_string())
+            .collect()
+    }
+
+    #[test]
+    fn test_oauth_client_alexa() {
+        assert!(
+            validate_oauth_client(
+                Some("https://pitangui.amazon.com/"),
+                Some("https://pitangui.amazon.com/api/skill/link/M2AAAAAAAAAAAA"),
+                &hosts(),
+            )
+            .is_ok()
+        );
+        // Skills redirect to the host of their region
+        assert!(
+            validate_oauth_client(
+                Some("https://pitangui.amazon.com/"),
+                Some("https://layla.amazon.com/api/skill/link/M2AAAAAAAAAAAA"),
+                &hosts(),
+            )
+            .is_ok()
+        );
+    }
+
+    #[test]
+    fn test_oauth_client_rejects_unknown() {
+        let google = Some("https://oauth-redirect.googleusercontent.com/");
+        assert!(
+            validate_oauth_client(google, Some("https://evil.example.com/r/x"), &hosts()).is_err()
+        );
+        assert!(
+            validate_oauth_client(Some("https://evil.example.com/"), google, &hosts()).is_err()
+        );
+        assert!(
+            validate_oauth_client(
+                google,
+                Some("http://oauth-redirect.googleusercontent.com/r/x"),
+                &hosts()
+            )
+            .is_err()
+        );
+        assert!(validate_oauth_client(google, None, &hosts()).is_err());
+    }
+
+    #[test]
+    fn test_query_param_decodes() {
+        let query = Some("client_id=https%3A%2F%2Flayla.amazon.com%2F&state=abc");
+        assert_eq!(
+            query_param(query, "client_id"),
+            Some("https://layla.amazon.com/".to_string())
+        );
+        assert_eq!(query_param(query, "redirect_uri"), None);
+    }
+
+    #[test]
+    fn test_token_request_grant_types() {
+        let form = vec![(
+            "Content-Type".to_string(),
+            "application/x-www-form-urlencoded; charset=utf-8".to_string(),
+        )];
+        let valid: [&[u8]; 3] = [
+            b"grant_type=authorization_code&code=abc&client_id=x",
+            b"grant_type=refresh_token&refresh_token=abc",
+            b"token=abc&action=revoke",
+        ];
+        for body in valid {
+            assert!(validate_token_request(&form, Some(body)).is_ok());
+        }
+
+        assert!(validate_token_request(&form, Some(b"grant_type=password&username=a")).is_err());
+        assert!(validate_token_request(&form, Some(b"action=login")).is_err());
+        assert!(validate_token_request(&form, None).is_err());
+    }
+
+    #[test]
+    fn test_token_request_must_be_form() {
+        let body: &[u8] = b"grant_type=refresh_token&refresh_token=abc";
+        let json = vec![("content-type".to_string(), "application/json".to_string())];
+        assert!(validate_token_request(&json, Some(body)).is_err());
+        assert!(validate_token_request(&[], Some(body)).is_err());
+    }
+
+    #[test]
+    fn test_auth_flow_requests() {
+        assert!(is_auth_flow_request("GET", "/auth/providers"));
+        assert!(is_auth_flow_request("GET", "/manifest.json"));
+        assert!(is_auth_flow_request("GET", "/frontend_latest/authorize.js"));
+        assert!(is_auth_flow_request("GET", "/static/icons/favicon.ico"));
+        assert!(is_auth_flow_request("POST", "/auth/login_flow"));
+        assert!(is_auth_flow_request("POST", "/auth/login_flow/abc123"));
+        assert!(is_auth_flow_request("DELETE", "/auth/login_flow/abc123"));
+
+        assert!(!is_auth_flow_request("POST", "/auth/providers"));
+        assert!(!is_auth_flow_request("POST", "/static/upload"));
+        assert!(!is_auth_flow_request("PUT", "/auth/login_flow/abc123"));
+        assert!(!is_auth_flow_request("GET", "/api/states"));
+        assert!(!is_auth_flow_request("GET", "/auth/providers/extra"));
+    }
+}
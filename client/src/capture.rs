@@ -0,0 +1,280 @@
+use base64::Engine;
+use base64::engine::general_purpose::STANDARD;
+use chrono::{DateTime, Utc};
+use common::error::ProxyError;
+use common::line_writer::LineWriter;
+use common::rotating_file::RotatingFile;
+use serde::{Deserialize, Serialize};
+use std::path::Path;
+use tracing::error;
+use url::form_urlencoded;
+
+/// Replaces the values of redacted headers, parameters and JSON fields
+pub const REDACTED: &str = "[REDACTED]";
+
+/// Headers whose values are never written to a capture
+const REDACTED_HEADERS: [&str; 5] = [
+    "authorization",
+    "proxy-authorization",
+    "cookie",
+    "set-cookie",
+    "x-ha-access",
+];
+
+/// Whether a query parameter, form field or JSON key holds a credential
+fn is_sensitive_key(key: &str) -> bool {
+    let key = key.to_lowercase();
+    key.contains("token")
+        || key.contains("password")
+        || key.contains("secret")
+        || key == "code"
+        || key == "authorization"
+}
+
+/// Body of a captured request or response, kept readable if it is text
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(tag = "encoding", content = "data", rename_all = "snake_case")]
+pub enum CapturedBody {
+    Utf8(String),
+    Base64(String),
+}
+
+impl CapturedBody {
+    fn new(body: &[u8], content_type: Option<&str>) -> Self {
+        match std::str::from_utf8(body) {
+            Ok(text) => CapturedBody::Utf8(redact_body(text, content_type)),
+            Err(_) => CapturedBody::Base64(STANDARD.encode(body)),
+        }
+    }
+
+    pub fn to_bytes(&self) -> Result<Vec<u8>, ProxyError> {
+        match self {
+            CapturedBody::Utf8(text) => Ok(text.as_bytes().to_vec()),
+            CapturedBody::Base64(data) => STANDARD
+                .decode(data)
+                .map_err(|e| ProxyError::InvalidRequest(format!("Invalid base64 body: {}", e))),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct CapturedRequest {
+    pub method: String,
+    pub path: String,
+    pub query: Option<String>,
+    pub headers: Vec<(String, String)>,
+    pub body: Option<CapturedBody>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct CapturedResponse {
+    pub status: u16,
+    pub headers: Vec<(String, String)>,
+    pub body: Option<CapturedBody>,
+}
+
+/// One line of a capture file
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct CaptureRecord {
+    pub timestamp: DateTime<Utc>,
+    pub request_id: String,
+    pub request: CapturedRequest,
+    /// None if the request failed before Home Assistant answered
+    pub response: Option<CapturedResponse>,
+    pub error: Option<String>,
+    pub duration_ms: u64,
+}
+
+impl CapturedRequest {
+    pub fn new(
+        method: &str,
+        path: &str,
+        query: Option<&str>,
+        headers: &[(String, String)],
+        body: Option<&[u8]>,
+    ) -> Self {
+        Self {
+            method: method.to_string(),
+            path: path.to_string(),
+            query: query.map(redact_form),
+            headers: redact_headers(headers),
+            body: body.map(|body| CapturedBody::new(body, content_type(headers))),
+        }
+    }
+}
+
+impl CapturedResponse {
+    pub fn new(status: u16, headers: &[(String, String)], body: Option<&[u8]>) -> Self {
+        Self {
+            status,
+            headers: redact_headers(headers),
+            body: body.map(|body| CapturedBody::new(body, content_type(headers))),
+        }
+    }
+}
+
+pub fn content_type(headers: &[(String, String)]) -> Option<&str> {
+    headers
+        .iter()
+        .find(|(name, _)| name.eq_ignore_ascii_case("content-type"))
+        .map(|(_, value)| value.as_str())
+}
+
+fn redact_headers(headers: &[(String, String)]) -> Vec<(String, String)> {
+    headers
+        .iter()
+        .map(|(name, value)| {
+            let redacted = REDACTED_HEADERS
+                .iter()
+                .any(|header| name.eq_ignore_ascii_case(header));
+            let value = if redacted { REDACTED } else { value };
+            (name.clone(), value.to_string())
+        })
+        .collect()
+}
+
+/// Redacts sensitive parameters of a query string or form body
+fn redact_form(form: &str) -> String {
+    form_urlencoded::Serializer::new(String::new())
+        .extend_pairs(form_urlencoded::parse(form.as_bytes()).map(|(key, value)| {
+            let value = if is_sensitive_key(&key) {
+                REDACTED.into()
+            } else {
+                value
+            };
+            (key, value)
+        }))
+        .finish()
+}
+
+fn redact_json(value: &mut serde_json::Value) {
+    match value {
+        serde_json::Value::Object(map) => {
+            for (key, value) in map.iter_mut() {
+                if is_sensitive_key(key) && !value.is_object() && !value.is_array() {
+                    *value = serde_json::Value::String(REDACTED.to_string());
+                } else {
+                    redact_json(value);
+                }
+            }
+        }
+        serde_json::Value::Array(values) => values.iter_mut().for_each(redact_json),
+        _ => {}
+    }
+}
+
+pub fn is_form(content_type: Option<&str>) -> bool {
+    content_type
+        .unwrap_or_default()
+        .starts_with("application/x-www-form-urlencoded")
+}
+
+fn redact_body(body: &str, content_type: Option<&str>) -> String {
+    if is_form(content_type) {
+        return redact_form(body);
+    }
+    match serde_json::from_str::<serde_json::Value>(body) {
+        Ok(mut json) => {
+            redact_json(&mut json);
+            json.to_string()
+        }
+        Err(_) => body.to_string(),
+    }
+}
+
+/// Writes request/response pairs going through the tunnel to a rotating
+/// JSON Lines file
+pub struct Capture {
+    writer: LineWriter,
+}
+
+impl Capture {
+    /// `max_size` is in bytes
+    pub fn open(path: &str, max_size: u64, max_files: usize) -> Result<Self, ProxyError> {
+        let mut file = RotatingFile::open(Path::new(path), max_size, max_files).map_err(|e| {
+            ProxyError::Config(format!("Failed to open capture file {}: {}", path, e))
+        })?;
+        let writer = LineWriter::spawn("capture file", move |line| file.write_line(line))
+            .map_err(|e| ProxyError::Config(format!("Failed to start capture writer: {}", e)))?;
+
+        Ok(Self { writer })
+    }
+
+    pub fn record(&self, record: &CaptureRecord) {
+        let line = match serde_json::to_string(record) {
+            Ok(line) => line,
+            Err(e) => {
+                error!(error = %e, "Failed to serialize capture record");
+                return;
+            }
+        };
+        self.writer.write_line(line);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn headers(values: &[(&str, &str)]) -> Vec<(String, String)> {
+        values
+            .iter()
+            .map(|(n, v)| (n.to_string(), v.to_string()))
+            .collect()
+    }
+
+    #[test]
+    fn test_redacts_headers_and_query() {
+        let request = CapturedRequest::new(
+            "GET",
+            "/auth/authorize",
+            Some("client_id=https%3A%2F%2Fpitangui.amazon.com&code=abc&state=1"),
+            &headers(&[("Authorization", "Bearer abc"), ("Accept", "*/*")]),
+            None,
+        );
+
+        assert_eq!(
+            request.query.as_deref(),
+            Some("client_id=https%3A%2F%2Fpitangui.amazon.com&code=%5BREDACTED%5D&state=1")
+        );
+        assert_eq!(request.headers[0].1, REDACTED);
+        assert_eq!(request.headers[1].1, "*/*");
+    }
+
+    #[test]
+    fn test_redacts_bodies() {
+        let directive = br#"{"directive":{"endpoint":{"scope":{"type":"BearerToken","token":"abc"}},"payload":{}}}"#;
+        let request = CapturedRequest::new(
+            "POST",
+            "/api/alexa/smart_home",
+            None,
+            &headers(&[("content-type", "application/json")]),
+            Some(directive),
+        );
+        let Some(CapturedBody::Utf8(body)) = request.body else {
+            panic!("expected text body");
+        };
+        assert_eq!(
+            body,
+            r#"{"directive":{"endpoint":{"scope":{"token":"[REDACTED]","type":"BearerToken"}},"payload":{}}}"#
+        );
+
+        let token_request = CapturedRequest::new(
+            "POST",
+            "/auth/token",
+            None,
+            &headers(&[("content-type", "application/x-www-form-urlencoded")]),
+            Some(b"grant_type=refresh_token&refresh_token=abc"),
+        );
+        let Some(CapturedBody::Utf8(body)) = token_request.body else {
+            panic!("expected text body");
+        };
+        assert_eq!(
+            body,
+            "grant_type=refresh_token&refresh_token=%5BREDACTED%5D"
+        );
+
+        let binary = CapturedResponse::new(200, &[], Some(&[0xff, 0x00]));
+        assert!(matches!(binary.body, Some(CapturedBody::Base64(_))));
+    }
+}
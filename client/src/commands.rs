@@ -0,0 +1,413 @@
+use crate::config::{Config, parse_config, supervisor_core_info};
+use crate::connection::CONNECT_TIMEOUT;
+use crate::tunnel_client::connect;
+use crate::{build_http_client, client_id_of};
+use anyhow::{Context as _, Result};
+use common::now_as_secs;
+use common::report::Report;
+use common::secrets::weak_secret;
+use common::tunnel::TunnelMessage;
+use ipnet::IpNet;
+use reqwest::{Client, StatusCode};
+use std::net::IpAddr;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant};
+use tokio::net::{UdpSocket, lookup_host};
+use url::Url;
+use yaml_rust2::{Yaml, YamlLoader};
+
+/// Time the heartbeat answer of the server is waited for
+const PONG_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Where Home Assistant's configuration is looked for if not given
+const HA_CONFIG_PATHS: [&str; 2] = [
+    "/homeassistant/configuration.yaml",
+    "/config/configuration.yaml",
+];
+
+/// Address never seen by Home Assistant, used to probe its proxy settings
+const PROBE_FORWARDED_FOR: &str = "192.0.2.1";
+
+async fn load_config(config_file: PathBuf) -> Result<Config> {
+    parse_config(config_file)
+        .await
+        .context("Invalid configuration")
+}
+
+/// Parses and validates the configuration, resolving `ha_server = "DETECT"`
+pub async fn check_config(config_file: PathBuf) -> Result<()> {
+    let config = load_config(config_file).await?;
+    let mut report = Report::default();
+
+    report.ok("Configuration is valid");
+    report.info(format!(
+        "ha_server: {} (ignore_ssl: {})",
+        config.ha_server, config.ha_ignore_ssl
+    ));
+    report.info(format!("ha_external_url: {}", config.ha_external_url));
+    report.info(format!(
+        "servers: {} ({:?}, {} connection(s) each)",
+        config.servers.join(", "),
+        config.server_mode,
+        config.pool_size
+    ));
+    report.info(format!(
+        "client_id: {}",
+        config.client_id.as_deref().unwrap_or("random")
+    ));
+    report.info(format!(
+        "alexa: {}, google: {}, auth_proxy: {}",
+        config.features.assistant_alexa,
+        config.features.assistant_google,
+        config.features.auth_proxy
+    ));
+
+    if !config.features.assistant_alexa && !config.features.assistant_google {
+        report.fail(
+            "Neither assistant_alexa nor assistant_google is enabled, all requests are rejected",
+        );
+    }
+    if !config.features.auth_proxy && config.ha_external_url == config.ha_server {
+        report.warn(
+            "ha_external_url is not set, account linking redirects to the local ha_server URL",
+        );
+    }
+    if let Some(reason) = weak_secret(&config.secret) {
+        report.warn(format!(
+            "The secret is weak, {}. Generate one with `ha-tunnel-server gen-secret`",
+            reason
+        ));
+    }
+    if let Some(path) = &config.capture_file {
+        report.warn(format!(
+            "Capture mode is on, request and response bodies are written to {}",
+            path
+        ));
+    }
+
+    report.finish()
+}
+
+/// Reaches Home Assistant and reports its version and SSL status. The
+/// version needs a long-lived access `token` unless running as add-on.
+pub async fn test_ha(config_file: PathBuf, token: Option<String>) -> Result<()> {
+    let config = load_config(config_file).await?;
+    let client = build_http_client(&config)?;
+    let mut report = Report::default();
+    let api_url = format!("{}/api/", config.ha_server.trim_end_matches('/'));
+
+    report.info(format!("Home Assistant: {}", config.ha_server));
+    let mut request = client.get(&api_url);
+    if let Some(token) = &token {
+        request = request.bearer_auth(token);
+    }
+    let start = Instant::now();
+    match request.send().await {
+        Ok(response) => {
+            let latency_ms = start.elapsed().as_millis();
+            match (response.status(), &token) {
+                (StatusCode::OK, _) => {
+                    report.ok(format!("API reachable, token accepted ({} ms)", latency_ms))
+                }
+                (StatusCode::UNAUTHORIZED, None) => {
+                    report.ok(format!("API reachable ({} ms)", latency_ms))
+                }
+                (StatusCode::UNAUTHORIZED, Some(_)) => report.fail(format!(
+                    "API reachable ({} ms), but the token was rejected",
+                    latency_ms
+                )),
+                (status, _) => report.fail(format!("API answered with status {}", status)),
+            }
+        }
+        Err(e) => {
+            report.fail(format!("Failed to reach Home Assistant: {}", e));
+            return report.finish();
+        }
+    }
+
+    if config.ha_server.starts_with("https://") {
+        let strict = Client::builder()
+            .timeout(Duration::from_secs(config.ha_timeout))
+            .build()?;
+        match strict.get(&api_url).send().await {
+            Ok(_) => report.ok("SSL enabled, certificate is valid"),
+            Err(_) if config.ha_ignore_ssl => {
+                report.warn("SSL enabled, certificate is not trusted (accepted, ha_ignore_ssl is set)")
+            }
+            Err(e) => report.fail(format!(
+                "SSL enabled, certificate is not trusted: {}. Set ha_ignore_ssl for self-signed certificates",
+                e
+            )),
+        }
+    } else {
+        report.info("SSL disabled");
+    }
+
+    let version = if let Some(token) = &token {
+        let config_url = format!("{}/api/config", config.ha_server.trim_end_matches('/'));
+        let response = client.get(&config_url).bearer_auth(token).send().await?;
+        response
+            .json::<serde_json::Value>()
+            .await
+            .ok()
+            .and_then(|config| config["version"].as_str().map(|v| v.to_string()))
+    } else if let Ok(supervisor_token) = std::env::var("SUPERVISOR_TOKEN") {
+        supervisor_core_info(&supervisor_token)
+            .await
+            .ok()
+            .and_then(|info| info.version)
+    } else {
+        None
+    };
+    match version {
+        Some(version) => report.info(format!("Version: {}", version)),
+        None => report.info("Version: unknown, pass --token with a long-lived access token"),
+    }
+
+    report.finish()
+}
+
+/// Connects and authenticates to every configured server, reporting the
+/// latencies, then disconnects
+pub async fn test_server(config_file: PathBuf) -> Result<()> {
+    let config = load_config(config_file).await?;
+    let client_id = client_id_of(&config);
+    let mut report = Report::default();
+
+    for server in &config.servers {
+        let start = Instant::now();
+        let result = tokio::time::timeout(
+            CONNECT_TIMEOUT,
+            connect(
+                &client_id,
+                server,
+                &config.secret,
+                &config.tunnel_compression,
+                config.priority,
+                config.weight,
+            ),
+        )
+        .await;
+        let (tx, mut rx, compression) = match result {
+            Ok(Ok(connection)) => connection,
+            Ok(Err(e)) => {
+                report.fail(format!("{}: {}", server, e));
+                continue;
+            }
+            Err(_) => {
+                report.fail(format!("{}: connection timed out", server));
+                continue;
+            }
+        };
+        let connect_ms = start.elapsed().as_millis();
+
+        let start = Instant::now();
+        tx.send(TunnelMessage::Ping {
+            timestamp: now_as_secs(),
+        })
+        .await?;
+        let pong = tokio::time::timeout(PONG_TIMEOUT, async {
+            while let Some(msg) = rx.recv().await {
+                if let TunnelMessage::Pong { .. } = msg {
+                    return true;
+                }
+            }
+            false
+        })
+        .await;
+
+        let compression = compression
+            .map(|c| format!("{:?}", c).to_lowercase())
+            .unwrap_or_else(|| "none".to_string());
+        match pong {
+            Ok(true) => report.ok(format!(
+                "{}: authenticated as {} in {} ms, heartbeat round trip {} ms, compression {}",
+                server,
+                client_id,
+                connect_ms,
+                start.elapsed().as_millis(),
+                compression
+            )),
+            _ => report.fail(format!(
+                "{}: authenticated as {} in {} ms, but no heartbeat answer",
+                server, client_id, connect_ms
+            )),
+        }
+    }
+
+    report.finish()
+}
+
+/// Address this client uses to reach `ha_server`, as Home Assistant sees it
+async fn local_address(ha_server: &str) -> Result<IpAddr> {
+    let url = Url::parse(ha_server).context("Invalid ha_server URL")?;
+    let host = url.host_str().context("ha_server has no host")?;
+    let port = url.port_or_known_default().unwrap_or(8123);
+    let remote = lookup_host((host.trim_matches(['[', ']']), port))
+        .await?
+        .next()
+        .context("ha_server host did not resolve")?;
+
+    // Connecting a UDP socket sends nothing, it only picks the route
+    let bind = if remote.is_ipv4() {
+        "0.0.0.0:0"
+    } else {
+        "[::]:0"
+    };
+    let socket = UdpSocket::bind(bind).await?;
+    socket.connect(remote).await?;
+    Ok(socket.local_addr()?.ip())
+}
+
+/// The `http:` section of Home Assistant's configuration, following `!include`
+fn http_config(path: &Path) -> Result<Option<Yaml>> {
+    let content = std::fs::read_to_string(path)
+        .with_context(|| format!("Failed to read {}", path.display()))?;
+    let docs = YamlLoader::load_from_str(&content)
+        .with_context(|| format!("Failed to parse {}", path.display()))?;
+    let http = docs.into_iter().next().map(|doc| doc["http"].clone());
+
+    match http {
+        // Tags are dropped by the parser, `http: !include http.yaml` is left as the file name
+        Some(Yaml::String(file)) => {
+            let included = path.with_file_name(file);
+            let content = std::fs::read_to_string(&included)
+                .with_context(|| format!("Failed to read {}", included.display()))?;
+            let docs = YamlLoader::load_from_str(&content)
+                .with_context(|| format!("Failed to parse {}", included.display()))?;
+            Ok(docs.into_iter().next())
+        }
+        Some(Yaml::Hash(http)) => Ok(Some(Yaml::Hash(http))),
+        _ => Ok(None),
+    }
+}
+
+fn trusted_proxies(http: &Yaml) -> Vec<String> {
+    match &http["trusted_proxies"] {
+        Yaml::Array(entries) => entries
+            .iter()
+            .filter_map(|entry| entry.as_str().map(|s| s.to_string()))
+            .collect(),
+        Yaml::String(entry) => vec![entry.clone()],
+        _ => vec![],
+    }
+}
+
+fn is_trusted(entry: &str, address: IpAddr) -> bool {
+    entry
+        .parse::<IpNet>()
+        .map(|net| net.contains(&address))
+        .or_else(|_| entry.parse::<IpAddr>().map(|ip| ip == address))
+        .unwrap_or(false)
+}
+
+/// Checks that Home Assistant accepts `X-Forwarded-For` from this client,
+/// live and in the `http:` section of its configuration
+pub async fn doctor(config_file: PathBuf, ha_config: Option<PathBuf>) -> Result<()> {
+    let config = load_config(config_file).await?;
+    let client = build_http_client(&config)?;
+    let mut report = Report::default();
+    // Without X-Forwarded-For Home Assistant doesn't check its proxy settings
+    let required = config.ha_pass_client_ip;
+    if !required {
+        report.info("ha_pass_client_ip is off, the proxy settings only matter once it is enabled");
+    }
+
+    let address = local_address(&config.ha_server).await?;
+    report.info(format!(
+        "Home Assistant sees this client as {} ({})",
+        address, config.ha_server
+    ));
+
+    let api_url = format!("{}/api/", config.ha_server.trim_end_matches('/'));
+    match client
+        .get(&api_url)
+        .header("x-forwarded-for", PROBE_FORWARDED_FOR)
+        .send()
+        .await
+    {
+        Ok(response) if response.status() == StatusCode::BAD_REQUEST => report.problem(
+            required,
+            format!(
+                "Home Assistant rejects forwarded requests from {}, set use_x_forwarded_for and add it to trusted_proxies",
+                address
+            ),
+        ),
+        Ok(_) => report.ok(format!(
+            "Home Assistant accepts forwarded requests from {}",
+            address
+        )),
+        Err(e) => report.fail(format!("Failed to reach Home Assistant: {}", e)),
+    }
+
+    let path = ha_config.or_else(|| {
+        HA_CONFIG_PATHS
+            .iter()
+            .map(PathBuf::from)
+            .find(|path| path.exists())
+    });
+    let Some(path) = path else {
+        report.warn("configuration.yaml not found, pass --ha-config to check it");
+        return report.finish();
+    };
+    report.info(format!("Checking {}", path.display()));
+
+    let Some(http) = http_config(&path)? else {
+        report.problem(
+            required,
+            "No http: section, use_x_forwarded_for and trusted_proxies are not set",
+        );
+        return report.finish();
+    };
+    if http["use_x_forwarded_for"].as_bool() == Some(true) {
+        report.ok("use_x_forwarded_for is enabled");
+    } else {
+        report.problem(required, "use_x_forwarded_for is not enabled");
+    }
+
+    let proxies = trusted_proxies(&http);
+    let network = IpNet::new(address, if address.is_ipv4() { 24 } else { 64 })
+        .map(|net| net.trunc().to_string())
+        .unwrap_or_else(|_| address.to_string());
+    match proxies.iter().find(|entry| is_trusted(entry, address)) {
+        Some(entry) => report.ok(format!(
+            "trusted_proxies entry {} contains {}",
+            entry, address
+        )),
+        None if proxies.is_empty() => report.problem(
+            required,
+            format!("trusted_proxies is not set, add {}", network),
+        ),
+        None => report.problem(
+            required,
+            format!(
+                "trusted_proxies ({}) don't contain {}, add {}",
+                proxies.join(", "),
+                address,
+                network
+            ),
+        ),
+    }
+
+    report.finish()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_trusted_proxies() {
+        let docs = YamlLoader::load_from_str(
+            "http:\n  use_x_forwarded_for: true\n  trusted_proxies:\n    - 172.30.33.0/24\n    - 10.0.0.5\n",
+        )
+        .unwrap();
+        let proxies = trusted_proxies(&docs[0]["http"]);
+        assert_eq!(proxies, vec!["172.30.33.0/24", "10.0.0.5"]);
+
+        assert!(is_trusted(&proxies[0], "172.30.33.7".parse().unwrap()));
+        assert!(is_trusted(&proxies[1], "10.0.0.5".parse().unwrap()));
+        assert!(!is_trusted(&proxies[0], "172.30.32.1".parse().unwrap()));
+        assert!(!is_trusted("my_secret", "10.0.0.5".parse().unwrap()));
+    }
+}
@@ -1,4 +1,9 @@
+use crate::auth::DEFAULT_REDIRECT_HOSTS;
 use anyhow::{Context, Result};
+use common::access_log::AccessLogFormat;
+use common::compression::Compression;
+use common::logging::{LogFormat, build_filter};
+use common::secrets::resolve_file_keys;
 use config::Config as ConfigParser;
 use serde::Deserialize;
 use std::path::PathBuf;
@@ -13,23 +18,70 @@ struct SupervisorResponse {
 }
 
 #[derive(Debug, Deserialize)]
-struct SupervisorCoreInfo {
-    ip_address: String,
-    port: u16,
-    ssl: bool,
+pub struct SupervisorCoreInfo {
+    pub ip_address: String,
+    pub port: u16,
+    pub ssl: bool,
+    pub version: Option<String>,
 }
 
 pub struct Features {
     pub assistant_alexa: bool,
     pub assistant_google: bool,
+    /// Serve the whole Home Assistant login flow through the tunnel instead
+    /// of redirecting to `ha_external_url`
+    pub auth_proxy: bool,
+}
+
+/// How the client uses the configured servers
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ServerMode {
+    /// Keep a single tunnel to the first reachable server in order of preference
+    Failover,
+    /// Keep a tunnel to every server at once
+    ActiveActive,
 }
 
 pub struct Config {
     pub log_level: Level,
+    /// `EnvFilter` directives overriding `log_level` for single targets
+    pub log_filter: String,
+    pub log_format: LogFormat,
+    /// OTLP/HTTP collector spans are exported to (None = disabled)
+    pub otlp_endpoint: Option<String>,
+
+    /// Access log target, `stdout` or a file path (None = disabled)
+    pub access_log: Option<String>,
+    pub access_log_format: AccessLogFormat,
+    /// Size in MB after which the access log file is rotated
+    pub access_log_max_size: u64,
+    /// Rotated access log files kept
+    pub access_log_max_files: usize,
 
-    pub server: String,
+    /// JSON Lines file request/response pairs are captured to (None = disabled)
+    pub capture_file: Option<String>,
+    /// Size in MB after which the capture file is rotated
+    pub capture_max_size: u64,
+    /// Rotated capture files kept
+    pub capture_max_files: usize,
+
+    /// Identifies the client on the servers (None = random on every start)
+    pub client_id: Option<String>,
+    /// Tunnel server WebSocket URLs, in order of preference
+    pub servers: Vec<String>,
+    pub server_mode: ServerMode,
+    /// Number of tunnel connections kept per server
+    pub pool_size: usize,
+    /// Sent to the server at auth, lower values are preferred by its `priority` strategy
+    pub priority: u32,
+    /// Sent to the server at auth, share of requests among clients with the same priority
+    pub weight: u32,
     pub reconnect_interval: u64,
     pub heartbeat_interval: u64,
+    /// Body compressions offered to the server, in order of preference
+    pub tunnel_compression: Vec<Compression>,
+    /// Seconds in-flight requests get to complete on shutdown
+    pub shutdown_grace_period: u64,
 
     pub ha_server: String,
     pub ha_external_url: String,
@@ -37,6 +89,9 @@ pub struct Config {
     pub ha_ignore_ssl: bool,
     pub ha_pass_client_ip: bool,
 
+    /// Hosts allowed as OAuth `client_id` and `redirect_uri` during account linking
+    pub auth_redirect_hosts: Vec<String>,
+
     pub secret: String,
 
     pub features: Features,
@@ -45,30 +100,94 @@ pub struct Config {
 pub async fn parse_config(config_file: PathBuf) -> Result<Config> {
     let settings = ConfigParser::builder()
         .set_default("log_level", "INFO")?
+        .set_default("log_filter", "")?
+        .set_default("log_format", "full")?
+        .set_default("access_log", "off")?
+        .set_default("access_log_format", "json")?
+        .set_default("access_log_max_size", 10)?
+        .set_default("access_log_max_files", 5)?
+        .set_default("capture_max_size", 10)?
+        .set_default("capture_max_files", 5)?
+        .set_default::<&str, Vec<String>>("servers", vec![])?
+        .set_default("server_mode", "failover")?
+        .set_default("pool_size", 1)?
+        .set_default("priority", 0)?
+        .set_default("weight", 1)?
         .set_default("reconnect_interval", 5)?
         .set_default("heartbeat_interval", 30)?
+        .set_default("tunnel_compression", vec!["zstd", "gzip"])?
+        .set_default("shutdown_grace_period", 30)?
         .set_default("ha_timeout", 10)?
         .set_default("ha_ignore_ssl", false)?
         .set_default("ha_pass_client_ip", false)?
         .set_default("assistant_alexa", true)?
         .set_default("assistant_google", true)?
+        .set_default("auth_proxy", false)?
+        .set_default::<&str, Vec<String>>("auth_redirect_hosts", vec![])?
         .add_source(config::File::with_name(config_file.to_str().unwrap()).required(false))
         .add_source(config::Environment::with_prefix("HA_TUNNEL"))
         .build()?;
+    // `capture_file` is where captures are written to, not a value to read
+    let settings = resolve_file_keys(settings, &["capture_file"])?;
 
     let log_level = settings.get_string("log_level")?.parse()?;
+    let log_filter = settings.get_string("log_filter")?;
+    build_filter(log_level, &log_filter)?;
+    let log_format = settings.get_string("log_format")?.parse()?;
+    let otlp_endpoint = settings
+        .get_string("otlp_endpoint")
+        .ok()
+        .filter(|endpoint| !endpoint.is_empty());
 
-    let server = settings.get_string("server")?;
-    let server = if let Some(stripped) = server.strip_prefix("https://") {
-        format!("wss://{}", stripped)
-    } else if let Some(stripped) = server.strip_prefix("http://") {
-        format!("ws://{}", stripped)
-    } else {
-        server
-    };
+    let access_log = settings
+        .get_string("access_log")
+        .ok()
+        .filter(|target| !target.is_empty() && target != "off");
+    let access_log_format = settings.get_string("access_log_format")?.parse()?;
+    let access_log_max_size = settings.get_int("access_log_max_size")?.try_into()?;
+    let access_log_max_files = settings.get_int("access_log_max_files")?.try_into()?;
+
+    let capture_file = settings
+        .get_string("capture_file")
+        .ok()
+        .filter(|path| !path.is_empty());
+    let capture_max_size = settings.get_int("capture_max_size")?.try_into()?;
+    let capture_max_files = settings.get_int("capture_max_files")?.try_into()?;
+
+    // `server` is kept for single server setups, `servers` can be a list or
+    // a comma separated string (e.g. from `HA_TUNNEL_SERVERS`)
+    let servers: Vec<String> = settings
+        .get_string("server")
+        .ok()
+        .into_iter()
+        .chain(settings.get::<Vec<String>>("servers").or_else(|_| {
+            settings
+                .get_string("servers")
+                .map(|s| s.split(',').map(|s| s.trim().to_string()).collect())
+        })?)
+        .filter(|server| !server.is_empty())
+        .map(|server| websocket_url(&server))
+        .collect();
+    if servers.is_empty() {
+        anyhow::bail!("At least one server has to be configured in `server` or `servers`");
+    }
+    let server_mode = parse_server_mode(&settings.get_string("server_mode")?)?;
+    let pool_size = settings.get_int("pool_size")?.try_into()?;
+    if pool_size == 0 {
+        anyhow::bail!("pool_size has to be at least 1");
+    }
+
+    let priority = settings.get_int("priority")?.try_into()?;
+    let weight = settings.get_int("weight")?.try_into()?;
 
     let reconnect_interval = settings.get_int("reconnect_interval")?.try_into()?;
     let heartbeat_interval = settings.get_int("heartbeat_interval")?.try_into()?;
+    let tunnel_compression = settings
+        .get::<Vec<String>>("tunnel_compression")?
+        .iter()
+        .map(|c| c.parse())
+        .collect::<Result<_, _>>()?;
+    let shutdown_grace_period = settings.get_int("shutdown_grace_period")?.try_into()?;
 
     let ha_server_config = settings.get_string("ha_server")?;
     let resolved = resolve_ha_server(&ha_server_config).await?;
@@ -88,15 +207,45 @@ pub async fn parse_config(config_file: PathBuf) -> Result<Config> {
 
     let assistant_alexa = settings.get_bool("assistant_alexa")?;
     let assistant_google = settings.get_bool("assistant_google")?;
+    let auth_proxy = settings.get_bool("auth_proxy")?;
+
+    let auth_redirect_hosts = DEFAULT_REDIRECT_HOSTS
+        .iter()
+        .map(|h| h.to_string())
+        .chain(settings.get::<Vec<String>>("auth_redirect_hosts")?)
+        .collect();
 
     let secret = settings.get_string("secret")?;
+    let client_id = settings
+        .get_string("client_id")
+        .ok()
+        .filter(|id| !id.is_empty());
 
     Ok(Config {
         log_level,
+        log_filter,
+        log_format,
+        otlp_endpoint,
+
+        access_log,
+        access_log_format,
+        access_log_max_size,
+        access_log_max_files,
 
-        server,
+        capture_file,
+        capture_max_size,
+        capture_max_files,
+
+        client_id,
+        servers,
+        server_mode,
+        pool_size,
+        priority,
+        weight,
         reconnect_interval,
         heartbeat_interval,
+        tunnel_compression,
+        shutdown_grace_period,
 
         ha_server,
         ha_external_url,
@@ -104,15 +253,36 @@ pub async fn parse_config(config_file: PathBuf) -> Result<Config> {
         ha_ignore_ssl,
         ha_pass_client_ip,
 
+        auth_redirect_hosts,
+
         secret,
 
         features: Features {
             assistant_alexa,
             assistant_google,
+            auth_proxy,
         },
     })
 }
 
+fn websocket_url(server: &str) -> String {
+    if let Some(stripped) = server.strip_prefix("https://") {
+        format!("wss://{}", stripped)
+    } else if let Some(stripped) = server.strip_prefix("http://") {
+        format!("ws://{}", stripped)
+    } else {
+        server.to_string()
+    }
+}
+
+fn parse_server_mode(mode: &str) -> Result<ServerMode> {
+    match mode.to_lowercase().as_str() {
+        "failover" | "active_passive" | "active-passive" => Ok(ServerMode::Failover),
+        "active_active" | "active-active" => Ok(ServerMode::ActiveActive),
+        other => anyhow::bail!("Unknown server_mode: {}", other),
+    }
+}
+
 struct ResolvedHaServer {
     url: String,
     uses_ssl: bool,
@@ -129,7 +299,20 @@ async fn resolve_ha_server(ha_server_config: &str) -> Result<ResolvedHaServer> {
 
     let supervisor_token = std::env::var("SUPERVISOR_TOKEN")
         .context("ha_server is set to DETECT but SUPERVISOR_TOKEN environment variable is not set. Are you running as a Home Assistant add-on?")?;
+    let core_info = supervisor_core_info(&supervisor_token).await?;
+
+    let uses_ssl = core_info.ssl;
+    let scheme = if uses_ssl { "https" } else { "http" };
+    let ha_server = format!("{}://{}:{}", scheme, core_info.ip_address, core_info.port);
 
+    Ok(ResolvedHaServer {
+        url: ha_server,
+        uses_ssl,
+    })
+}
+
+/// Queries the Supervisor API for the address and version of Home Assistant
+pub async fn supervisor_core_info(supervisor_token: &str) -> Result<SupervisorCoreInfo> {
     let client = reqwest::Client::new();
     let response = client
         .get(SUPERVISOR_API_URL)
@@ -152,15 +335,5 @@ async fn resolve_ha_server(ha_server_config: &str) -> Result<ResolvedHaServer> {
         .await
         .context("Failed to parse Supervisor API response")?;
 
-    let uses_ssl = supervisor_info.data.ssl;
-    let scheme = if uses_ssl { "https" } else { "http" };
-    let ha_server = format!(
-        "{}://{}:{}",
-        scheme, supervisor_info.data.ip_address, supervisor_info.data.port
-    );
-
-    Ok(ResolvedHaServer {
-        url: ha_server,
-        uses_ssl,
-    })
+    Ok(supervisor_info.data)
 }
@@ -0,0 +1,660 @@
+use crate::capture::Capture;
+use crate::config::Config;
+use crate::proxy::handle_request;
+use crate::tunnel_client::connect;
+use common::access_log::AccessLog;
+use common::compression::Compression;
+use common::error::ProxyError;
+use common::now_as_secs;
+use common::tunnel::TunnelMessage;
+use reqwest::Client;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use tokio::sync::{mpsc, watch};
+use tokio::task::{JoinHandle, JoinSet};
+use tokio::time::{MissedTickBehavior, sleep};
+use tracing::{debug, error, info, warn};
+
+/// Maximum time to establish and authenticate a tunnel connection
+pub const CONNECT_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Heartbeats that may go unanswered before a connection is considered dead
+const MAX_MISSED_HEARTBEATS: u32 = 3;
+
+/// Interval at which a tunnel connected to a fallback server checks whether
+/// a preferred server is reachable again
+const FAILBACK_INTERVAL: Duration = Duration::from_secs(60);
+
+/// Sender, receiver and negotiated compression of an authenticated connection
+type Connection = (
+    mpsc::Sender<TunnelMessage>,
+    mpsc::Receiver<TunnelMessage>,
+    Option<Compression>,
+);
+
+/// Requests in flight on a connection, each yielding whether its response
+/// could be sent
+type Requests = JoinSet<bool>;
+
+/// Configuration and HTTP client used by the tunnels, replaced as a whole on reload
+#[derive(Clone)]
+pub struct Context {
+    pub config: Arc<Config>,
+    pub client: Client,
+    pub access_log: Arc<AccessLog>,
+    /// Set if capture mode is on
+    pub capture: Option<Arc<Capture>>,
+}
+
+/// Connection health of a single server
+struct ServerHealth {
+    server: String,
+    /// Position in the configured servers, lower is preferred
+    preference: usize,
+    consecutive_failures: u32,
+    /// The server announced it is going away, tried last until a connection
+    /// is established again
+    going_away: bool,
+}
+
+impl ServerHealth {
+    fn new(server: String, preference: usize) -> Self {
+        Self {
+            server,
+            preference,
+            consecutive_failures: 0,
+            going_away: false,
+        }
+    }
+
+    fn connected(&mut self) {
+        if self.consecutive_failures > 0 {
+            info!(server = %self.server, failures = self.consecutive_failures, "Server recovered");
+        }
+        self.consecutive_failures = 0;
+    }
+
+    fn failed(&mut self, error: &ProxyError) {
+        self.consecutive_failures += 1;
+        error!(
+            server = %self.server,
+            failures = self.consecutive_failures,
+            error = %error,
+            "Failed to connect to server"
+        );
+    }
+}
+
+/// Orders servers to connect to, those going away last, then those that
+/// failed recently and otherwise by preference
+fn sort_by_health(health: &mut [ServerHealth]) {
+    health.sort_by_key(|h| (h.going_away, h.consecutive_failures, h.preference));
+}
+
+/// Why a tunnel connection ended
+enum ConnectionEnd {
+    /// The tunnel was asked to stop and the connection is drained
+    Stopped,
+    /// The connection broke or stopped answering heartbeats
+    Lost,
+    /// The server is going away, requests in flight still arrive within the grace period
+    ServerGoingAway(Duration),
+    /// A preferred server is reachable again, connected to on the contained connection
+    FailBack(String, Connection),
+}
+
+async fn connect_to(
+    client_id: &str,
+    server: &str,
+    config: &Config,
+) -> Result<Connection, ProxyError> {
+    tokio::time::timeout(
+        CONNECT_TIMEOUT,
+        connect(
+            client_id,
+            server,
+            &config.secret,
+            &config.tunnel_compression,
+            config.priority,
+            config.weight,
+        ),
+    )
+    .await
+    .unwrap_or_else(|_| Err(ProxyError::Connection("Connection timed out".to_string())))
+}
+
+/// Connects to the first reachable of `servers`
+async fn probe_servers(
+    client_id: &str,
+    servers: &[String],
+    config: &Config,
+) -> Option<(String, Connection)> {
+    for server in servers {
+        match connect_to(client_id, server, config).await {
+            Ok(connection) => return Some((server.clone(), connection)),
+            Err(e) => debug!(server = %server, error = %e, "Preferred server still unreachable"),
+        }
+    }
+    None
+}
+
+/// Keeps one tunnel connection up to the first reachable of `servers` (in
+/// order of preference, servers that failed recently last) until `stop` flips
+/// to true. While connected to a fallback server, the preferred ones are
+/// probed and the tunnel moves back once one of them is reachable. Stopping
+/// drains the connection before returning.
+pub async fn run_tunnel(
+    client_id: String,
+    servers: Vec<String>,
+    mut context: watch::Receiver<Context>,
+    mut stop: watch::Receiver<bool>,
+) {
+    let mut health: Vec<ServerHealth> = servers
+        .into_iter()
+        .enumerate()
+        .map(|(preference, server)| ServerHealth::new(server, preference))
+        .collect();
+
+    // Connections the server announced it is going away on, still finishing their requests
+    let mut draining = JoinSet::new();
+    // Connection to a preferred server found while connected to a fallback one
+    let mut failback = None;
+
+    loop {
+        if *stop.borrow() {
+            break;
+        }
+
+        let ctx = context.borrow().clone();
+        sort_by_health(&mut health);
+
+        let mut connection = failback.take().and_then(|(server, conn)| {
+            health
+                .iter()
+                .position(|h| h.server == server)
+                .map(|index| (index, conn))
+        });
+        if connection.is_none() {
+            for (index, server) in health.iter_mut().enumerate() {
+                match connect_to(&client_id, &server.server, &ctx.config).await {
+                    Ok(conn) => {
+                        connection = Some((index, conn));
+                        break;
+                    }
+                    Err(e) => server.failed(&e),
+                }
+            }
+        }
+
+        match connection {
+            Some((index, (tx, mut rx, compression))) => {
+                let mut requests = Requests::new();
+                let mut preferred: Vec<&ServerHealth> = health
+                    .iter()
+                    .filter(|h| h.preference < health[index].preference)
+                    .collect();
+                preferred.sort_by_key(|h| h.preference);
+                let preferred: Vec<String> = preferred.iter().map(|h| h.server.clone()).collect();
+
+                // Servers that went away are only avoided until connected elsewhere
+                health.iter_mut().for_each(|h| h.going_away = false);
+                let server = &mut health[index];
+                server.connected();
+                info!(server = %server.server, "Connected to server");
+
+                match serve_connection(
+                    &client_id,
+                    &mut context,
+                    &mut stop,
+                    &tx,
+                    &mut rx,
+                    &mut requests,
+                    compression,
+                    &preferred,
+                )
+                .await
+                {
+                    ConnectionEnd::Stopped => break,
+                    ConnectionEnd::FailBack(preferred_server, connection) => {
+                        // Requests in flight on the fallback server finish in the background
+                        info!(server = %server.server, preferred_server = %preferred_server, "Preferred server reachable again, failing back");
+                        let ctx = context.borrow().clone();
+                        let client_id = client_id.clone();
+                        draining.spawn(async move {
+                            leave_connection(
+                                &ctx,
+                                &client_id,
+                                compression,
+                                &tx,
+                                &mut rx,
+                                &mut requests,
+                            )
+                            .await;
+                        });
+                        failback = Some((preferred_server, connection));
+                        continue;
+                    }
+                    ConnectionEnd::ServerGoingAway(grace_period) => {
+                        // Keep answering on the old connection until the server closes it,
+                        // while a replacement connection is established right away
+                        info!(server = %server.server, "Server is going away, reconnecting");
+                        server.going_away = true;
+                        let ctx = context.borrow().clone();
+                        let client_id = client_id.clone();
+                        draining.spawn(async move {
+                            drain_connection(
+                                &ctx,
+                                &client_id,
+                                compression,
+                                &tx,
+                                &mut rx,
+                                &mut requests,
+                                grace_period,
+                            )
+                            .await;
+                        });
+                        continue;
+                    }
+                    ConnectionEnd::Lost => {
+                        warn!(server = %server.server, "Connection to server lost");
+                        server.consecutive_failures += 1;
+                    }
+                }
+            }
+            None => {
+                error!("No server reachable");
+            }
+        }
+
+        let reconnect_interval = Duration::from_secs(context.borrow().config.reconnect_interval);
+        info!(
+            "Reconnecting in {} seconds...",
+            reconnect_interval.as_secs()
+        );
+
+        // Check stop before reconnect sleep
+        tokio::select! {
+            _ = stopped(&mut stop) => break,
+            _ = sleep(reconnect_interval) => {}
+        }
+    }
+
+    // Let connections that were already going away finish their requests
+    let grace_period = Duration::from_secs(context.borrow().config.shutdown_grace_period);
+    if tokio::time::timeout(grace_period, draining.join_all())
+        .await
+        .is_err()
+    {
+        warn!("Grace period expired with requests still in flight");
+    }
+}
+
+/// Handles requests on an established connection until it ends, each in its
+/// own task so a slow one doesn't hold up the others. Requests still in flight
+/// are left in `requests`. The `preferred` servers are probed regularly to
+/// fail back to them.
+#[allow(clippy::too_many_arguments)]
+async fn serve_connection(
+    client_id: &str,
+    context: &mut watch::Receiver<Context>,
+    stop: &mut watch::Receiver<bool>,
+    tx: &mpsc::Sender<TunnelMessage>,
+    rx: &mut mpsc::Receiver<TunnelMessage>,
+    requests: &mut Requests,
+    compression: Option<Compression>,
+    preferred: &[String],
+) -> ConnectionEnd {
+    let mut ctx = context.borrow_and_update().clone();
+
+    let mut heartbeat_interval = Duration::from_secs(ctx.config.heartbeat_interval);
+    let mut heartbeat_handle = spawn_heartbeat(tx.clone(), heartbeat_interval);
+    let mut health_check = tokio::time::interval(heartbeat_interval);
+    let mut last_pong = Instant::now();
+
+    let mut failback_check = tokio::time::interval_at(
+        tokio::time::Instant::now() + FAILBACK_INTERVAL,
+        FAILBACK_INTERVAL,
+    );
+    failback_check.set_missed_tick_behavior(MissedTickBehavior::Delay);
+    let (probe_tx, mut probe_rx) = mpsc::channel(1);
+    let mut probe: Option<JoinHandle<()>> = None;
+
+    let end = loop {
+        tokio::select! {
+            _ = stopped(stop) => {
+                heartbeat_handle.abort();
+                leave_connection(&ctx, client_id, compression, tx, rx, requests).await;
+                break ConnectionEnd::Stopped;
+            }
+            _ = failback_check.tick(), if !preferred.is_empty() && probe.as_ref().is_none_or(|p| p.is_finished()) => {
+                let (probe_tx, client_id, servers, config) = (
+                    probe_tx.clone(),
+                    client_id.to_string(),
+                    preferred.to_vec(),
+                    ctx.config.clone(),
+                );
+                probe = Some(tokio::spawn(async move {
+                    if let Some(found) = probe_servers(&client_id, &servers, &config).await {
+                        let _ = probe_tx.send(found).await;
+                    }
+                }));
+            }
+            Some((server, connection)) = probe_rx.recv() => {
+                break ConnectionEnd::FailBack(server, connection);
+            }
+            Ok(()) = context.changed() => {
+                ctx = context.borrow_and_update().clone();
+
+                let new_interval = Duration::from_secs(ctx.config.heartbeat_interval);
+                if new_interval != heartbeat_interval {
+                    heartbeat_handle.abort();
+                    heartbeat_interval = new_interval;
+                    heartbeat_handle = spawn_heartbeat(tx.clone(), heartbeat_interval);
+                    health_check = tokio::time::interval(heartbeat_interval);
+                }
+            }
+            _ = health_check.tick() => {
+                if last_pong.elapsed() > heartbeat_interval * MAX_MISSED_HEARTBEATS {
+                    warn!(
+                        last_pong_s = last_pong.elapsed().as_secs(),
+                        "Server stopped answering heartbeats"
+                    );
+                    break ConnectionEnd::Lost;
+                }
+            }
+            Some(sent) = requests.join_next(), if !requests.is_empty() => {
+                if let Ok(false) = sent {
+                    error!("Failed to send response, connection may be closed");
+                    break ConnectionEnd::Lost;
+                }
+            }
+            msg = rx.recv() => {
+                match msg {
+                    Some(TunnelMessage::GoingAway { grace_period }) => {
+                        break ConnectionEnd::ServerGoingAway(Duration::from_secs(grace_period));
+                    }
+                    Some(TunnelMessage::Ping { timestamp }) => {
+                        // The server measures the round trip time of the connection
+                        if tx.send(TunnelMessage::Pong { timestamp }).await.is_err() {
+                            break ConnectionEnd::Lost;
+                        }
+                    }
+                    Some(TunnelMessage::Pong { timestamp }) => {
+                        last_pong = Instant::now();
+                        debug!(latency_s = %now_as_secs().saturating_sub(timestamp), "Pong received");
+                    }
+                    Some(msg) => {
+                        spawn_request(requests, &ctx, client_id, compression, tx, msg);
+                    }
+                    None => {
+                        break ConnectionEnd::Lost;
+                    }
+                }
+            }
+        }
+    };
+
+    heartbeat_handle.abort();
+    if let Some(probe) = probe {
+        probe.abort();
+    }
+    end
+}
+
+/// Handles a request in the background, sending its response on `tx`
+fn spawn_request(
+    requests: &mut Requests,
+    ctx: &Context,
+    client_id: &str,
+    compression: Option<Compression>,
+    tx: &mpsc::Sender<TunnelMessage>,
+    msg: TunnelMessage,
+) {
+    let (ctx, client_id, tx) = (ctx.clone(), client_id.to_string(), tx.clone());
+    requests.spawn(async move {
+        match handle_request(&ctx, &client_id, compression, msg).await {
+            Some(response) => tx.send(response).await.is_ok(),
+            None => true,
+        }
+    });
+}
+
+/// Asks the server to stop sending requests on a connection and finishes the
+/// ones in flight
+async fn leave_connection(
+    ctx: &Context,
+    client_id: &str,
+    compression: Option<Compression>,
+    tx: &mpsc::Sender<TunnelMessage>,
+    rx: &mut mpsc::Receiver<TunnelMessage>,
+    requests: &mut Requests,
+) {
+    info!("Draining connection");
+    let grace_period = Duration::from_secs(ctx.config.shutdown_grace_period);
+    let going_away = TunnelMessage::GoingAway {
+        grace_period: grace_period.as_secs(),
+    };
+    if tx.send(going_away).await.is_ok() {
+        drain_connection(ctx, client_id, compression, tx, rx, requests, grace_period).await;
+    }
+}
+
+/// Resolves once the tunnel was asked to stop (or its owner is gone)
+async fn stopped(stop: &mut watch::Receiver<bool>) {
+    let _ = stop.wait_for(|stop| *stop).await;
+}
+
+fn spawn_heartbeat(
+    tx: mpsc::Sender<TunnelMessage>,
+    heartbeat_interval: Duration,
+) -> JoinHandle<()> {
+    tokio::spawn(async move {
+        let mut interval = tokio::time::interval(heartbeat_interval);
+        loop {
+            interval.tick().await;
+            let ping = TunnelMessage::Ping {
+                timestamp: now_as_secs(),
+            };
+            if tx.send(ping).await.is_err() {
+                break;
+            }
+        }
+    })
+}
+
+/// Keeps answering requests received on a connection that is going away
+/// until the server closed it and the requests in flight are done, or the
+/// grace period expired
+async fn drain_connection(
+    ctx: &Context,
+    client_id: &str,
+    compression: Option<Compression>,
+    tx: &mpsc::Sender<TunnelMessage>,
+    rx: &mut mpsc::Receiver<TunnelMessage>,
+    requests: &mut Requests,
+    grace_period: Duration,
+) {
+    let result = tokio::time::timeout(grace_period, async {
+        let mut open = true;
+        while open || !requests.is_empty() {
+            tokio::select! {
+                msg = rx.recv(), if open => match msg {
+                    Some(msg) => spawn_request(requests, ctx, client_id, compression, tx, msg),
+                    None => open = false,
+                },
+                Some(_) = requests.join_next(), if !requests.is_empty() => {}
+            }
+        }
+    })
+    .await;
+
+    match result {
+        Ok(()) => debug!("Connection drained"),
+        Err(_) => warn!("Grace period expired with requests still in flight"),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::parse_config;
+    use common::access_log::AccessLogFormat;
+    use std::collections::HashMap;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio::net::TcpListener;
+
+    fn order(health: &[ServerHealth]) -> Vec<&str> {
+        health.iter().map(|h| h.server.as_str()).collect()
+    }
+
+    #[test]
+    fn test_sort_by_health() {
+        let mut health: Vec<ServerHealth> = ["primary", "secondary", "tertiary"]
+            .into_iter()
+            .enumerate()
+            .map(|(preference, server)| ServerHealth::new(server.to_string(), preference))
+            .collect();
+
+        let error = ProxyError::Connection("refused".to_string());
+        health[0].failed(&error);
+        sort_by_health(&mut health);
+        assert_eq!(order(&health), ["secondary", "tertiary", "primary"]);
+
+        health[0].failed(&error);
+        sort_by_health(&mut health);
+        assert_eq!(order(&health), ["tertiary", "primary", "secondary"]);
+
+        // A server that recovered takes its configured place again
+        health[1].connected();
+        health[2].connected();
+        sort_by_health(&mut health);
+        assert_eq!(order(&health), ["primary", "secondary", "tertiary"]);
+    }
+
+    #[test]
+    fn test_going_away_server_is_tried_last() {
+        let mut health: Vec<ServerHealth> = ["primary", "secondary", "tertiary"]
+            .into_iter()
+            .enumerate()
+            .map(|(preference, server)| ServerHealth::new(server.to_string(), preference))
+            .collect();
+
+        health[0].going_away = true;
+        sort_by_health(&mut health);
+        assert_eq!(order(&health), ["secondary", "tertiary", "primary"]);
+
+        // Even servers that failed recently are tried first
+        let error = ProxyError::Connection("refused".to_string());
+        health[0].failed(&error);
+        sort_by_health(&mut health);
+        assert_eq!(order(&health), ["tertiary", "secondary", "primary"]);
+    }
+
+    /// Home Assistant stand-in answering requests with `slow` in their ID
+    /// only after a long delay
+    async fn spawn_ha() -> String {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move {
+            while let Ok((mut socket, _)) = listener.accept().await {
+                tokio::spawn(async move {
+                    let mut head = Vec::new();
+                    let mut buf = [0; 1024];
+                    while !head.windows(4).any(|w| w == b"\r\n\r\n") {
+                        match socket.read(&mut buf).await {
+                            Ok(0) | Err(_) => return,
+                            Ok(n) => head.extend_from_slice(&buf[..n]),
+                        }
+                    }
+                    if String::from_utf8_lossy(&head).contains("slow") {
+                        sleep(Duration::from_secs(5)).await;
+                    }
+                    let _ = socket
+                        .write_all(
+                            b"HTTP/1.1 200 OK\r\ncontent-length: 0\r\nconnection: close\r\n\r\n",
+                        )
+                        .await;
+                });
+            }
+        });
+        format!("http://{}", addr)
+    }
+
+    async fn test_context(ha_server: &str) -> Context {
+        let path =
+            std::env::temp_dir().join(format!("ha-tunnel-connection-{}.toml", std::process::id()));
+        std::fs::write(
+            &path,
+            format!(
+                "servers = [\"ws://localhost:1\"]\nsecret = \"test\"\nha_server = \"{}\"\n",
+                ha_server
+            ),
+        )
+        .unwrap();
+        let config = parse_config(path.clone()).await.unwrap();
+        std::fs::remove_file(&path).unwrap();
+
+        Context {
+            config: Arc::new(config),
+            client: Client::new(),
+            access_log: Arc::new(AccessLog::open(None, AccessLogFormat::default(), 0, 0).unwrap()),
+            capture: None,
+        }
+    }
+
+    fn alexa_request(id: &str) -> TunnelMessage {
+        TunnelMessage::HttpRequest {
+            request_id: id.to_string(),
+            method: "POST".to_string(),
+            path: "/api/alexa/smart_home".to_string(),
+            query: None,
+            headers: vec![],
+            body: Some(b"{}".to_vec()),
+            body_compression: None,
+            source_ip: None,
+            correlation_id: None,
+            trace_context: HashMap::new(),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_slow_request_does_not_block_connection() {
+        let ctx = test_context(&spawn_ha().await).await;
+        let (_context_tx, mut context) = watch::channel(ctx);
+        let (_stop_tx, mut stop) = watch::channel(false);
+        let (to_client, mut rx) = mpsc::channel(8);
+        let (tx, mut from_client) = mpsc::channel(8);
+
+        let connection = tokio::spawn(async move {
+            let mut requests = Requests::new();
+            serve_connection(
+                "test",
+                &mut context,
+                &mut stop,
+                &tx,
+                &mut rx,
+                &mut requests,
+                None,
+                &[],
+            )
+            .await;
+        });
+        to_client.send(alexa_request("slow")).await.unwrap();
+        to_client.send(alexa_request("fast")).await.unwrap();
+
+        let response = tokio::time::timeout(Duration::from_secs(2), async {
+            loop {
+                match from_client.recv().await {
+                    Some(TunnelMessage::HttpResponse { request_id, .. }) => break request_id,
+                    Some(_) => continue,
+                    None => panic!("connection closed"),
+                }
+            }
+        })
+        .await
+        .expect("fast request waited for the slow one");
+        assert_eq!(response, "fast");
+
+        connection.abort();
+    }
+}
@@ -1,137 +1,361 @@
-use crate::config::parse_config;
-use crate::proxy::handle_request;
-use crate::tunnel_client::connect;
+use crate::capture::Capture;
+use crate::config::{Config, ServerMode, parse_config};
+use crate::connection::{Context, run_tunnel};
 use anyhow::Result;
-use clap::Parser;
+use clap::{Parser, Subcommand};
+use common::access_log::AccessLog;
 use common::error::ProxyError;
-use common::now_as_secs;
-use common::tunnel::TunnelMessage;
+use common::logging::{init_logging, reload_log_filter};
+use common::reload::watch_config;
+use common::secrets::warn_weak_secret;
 use reqwest::Client;
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
 use std::time::Duration;
 use tokio::signal;
 use tokio::sync::watch;
-use tokio::time::sleep;
+use tokio::task::JoinSet;
 use tracing::{error, info, warn};
 use uuid::Uuid;
 
+mod auth;
+mod capture;
+mod commands;
 mod config;
+mod connection;
 mod proxy;
+mod replay;
 mod tunnel_client;
 
 #[derive(Parser, Debug)]
 struct Args {
     /// Path to configuration file
-    #[arg(short, long, default_value = "config.toml")]
+    #[arg(short, long, default_value = "config.toml", global = true)]
     config: PathBuf,
+
+    #[command(subcommand)]
+    command: Option<Command>,
 }
 
-#[tokio::main]
-async fn main() -> Result<()> {
-    let args = Args::parse();
-    let config = parse_config(args.config).await?;
+#[derive(Subcommand, Debug)]
+enum Command {
+    /// Parses and validates the configuration, resolving `ha_server = "DETECT"`
+    CheckConfig,
+    /// Reaches Home Assistant and reports its version and SSL status
+    TestHa {
+        /// Long-lived access token to check and read the version with
+        #[arg(long)]
+        token: Option<String>,
+    },
+    /// Connects and authenticates to the servers, reports the latencies and exits
+    TestServer,
+    /// Checks that Home Assistant trusts this client as proxy (`use_x_forwarded_for`, `trusted_proxies`)
+    Doctor {
+        /// Home Assistant `configuration.yaml` (default: /homeassistant or /config)
+        #[arg(long)]
+        ha_config: Option<PathBuf>,
+    },
+    /// Sends the requests of a capture file to Home Assistant again
+    Replay {
+        /// Capture file written in capture mode
+        file: PathBuf,
+        /// Home Assistant URL (default: `ha_server` of the configuration)
+        #[arg(long)]
+        ha_server: Option<String>,
+        /// Access token used in place of redacted credentials
+        #[arg(long)]
+        token: Option<String>,
+        /// Extra header as `Name: value`, may be repeated
+        #[arg(long = "header", value_parser = parse_header)]
+        headers: Vec<(String, String)>,
+        /// Accept invalid TLS certificates of Home Assistant
+        #[arg(long)]
+        ignore_ssl: bool,
+    },
+}
+
+fn parse_header(header: &str) -> Result<(String, String), String> {
+    header
+        .split_once(':')
+        .map(|(name, value)| (name.trim().to_string(), value.trim().to_string()))
+        .filter(|(name, _)| !name.is_empty())
+        .ok_or_else(|| format!("expected `Name: value`, got `{}`", header))
+}
 
-    tracing_subscriber::fmt()
-        .with_max_level(config.log_level)
-        .with_target(false)
-        .init();
+async fn run_command(command: Command, config_file: PathBuf) -> Result<()> {
+    match command {
+        Command::CheckConfig => commands::check_config(config_file).await,
+        Command::TestHa { token } => commands::test_ha(config_file, token).await,
+        Command::TestServer => commands::test_server(config_file).await,
+        Command::Doctor { ha_config } => commands::doctor(config_file, ha_config).await,
+        Command::Replay {
+            file,
+            ha_server,
+            token,
+            headers,
+            ignore_ssl,
+        } => {
+            let (ha_server, ignore_ssl) = match ha_server {
+                Some(ha_server) => (ha_server, ignore_ssl),
+                None => {
+                    let config = parse_config(config_file).await?;
+                    (config.ha_server, ignore_ssl || config.ha_ignore_ssl)
+                }
+            };
+            replay::replay(&file, &ha_server, token.as_deref(), &headers, ignore_ssl).await
+        }
+    }
+}
 
-    info!(ha_server = %config.ha_server, ignore_ssl = %config.ha_ignore_ssl, "Starting Home Assistant Tunnel Client");
+/// Settings that only take effect on new tunnel connections
+fn connection_changed(current: &Config, new: &Config) -> bool {
+    current.client_id != new.client_id
+        || current.servers != new.servers
+        || current.server_mode != new.server_mode
+        || current.pool_size != new.pool_size
+        || current.priority != new.priority
+        || current.weight != new.weight
+        || current.secret != new.secret
+}
 
-    let reconnect_interval = Duration::from_secs(config.reconnect_interval);
-    let heartbeat_interval = Duration::from_secs(config.heartbeat_interval);
-    let client_id = Uuid::new_v4().to_string();
+/// The configured client ID or a random one
+fn client_id_of(config: &Config) -> String {
+    config
+        .client_id
+        .clone()
+        .unwrap_or_else(|| Uuid::new_v4().to_string())
+}
 
-    let client = Client::builder()
+fn build_http_client(config: &Config) -> Result<Client, ProxyError> {
+    Client::builder()
         .timeout(Duration::from_secs(config.ha_timeout))
         .danger_accept_invalid_certs(config.ha_ignore_ssl)
         .build()
-        .map_err(|e| ProxyError::Config(e.to_string()))?;
+        .map_err(|e| ProxyError::Config(e.to_string()))
+}
 
-    // Create shutdown channel
-    let (shutdown_tx, mut shutdown_rx) = watch::channel(false);
+fn open_capture(config: &Config) -> Result<Option<Arc<Capture>>, ProxyError> {
+    config
+        .capture_file
+        .as_deref()
+        .map(|path| {
+            Capture::open(
+                path,
+                config.capture_max_size * 1024 * 1024,
+                config.capture_max_files,
+            )
+            .map(Arc::new)
+        })
+        .transpose()
+}
 
-    // Spawn signal handler
-    tokio::spawn(async move {
-        let _ = signal::ctrl_c().await;
-        info!("Shutdown signal received, starting graceful shutdown");
-        let _ = shutdown_tx.send(true);
-    });
+fn open_access_log(config: &Config) -> Result<AccessLog, ProxyError> {
+    AccessLog::open(
+        config.access_log.as_deref(),
+        config.access_log_format,
+        config.access_log_max_size * 1024 * 1024,
+        config.access_log_max_files,
+    )
+}
+
+/// Spawns the tunnels for the configured servers: a pool failing over between
+/// them, or a pool per server
+fn spawn_tunnels(
+    tunnels: &mut JoinSet<()>,
+    client_id: &str,
+    config: &Config,
+    context: &watch::Sender<Context>,
+    stop: &watch::Sender<bool>,
+) {
+    let server_groups = match config.server_mode {
+        ServerMode::Failover => vec![config.servers.clone()],
+        ServerMode::ActiveActive => config.servers.iter().map(|s| vec![s.clone()]).collect(),
+    };
 
-    'main_loop: loop {
-        // Check for shutdown before attempting connection
-        if *shutdown_rx.borrow() {
-            break;
+    for servers in server_groups {
+        for _ in 0..config.pool_size {
+            tunnels.spawn(run_tunnel(
+                client_id.to_string(),
+                servers.clone(),
+                context.subscribe(),
+                stop.subscribe(),
+            ));
         }
+    }
+}
 
-        match connect(&client_id, &config.server, &config.secret).await {
-            Ok((tx, mut rx)) => {
-                info!("Connected to server");
-
-                // Spawn heartbeat task
-                let heartbeat_tx = tx.clone();
-                let heartbeat_handle = tokio::spawn(async move {
-                    let mut interval = tokio::time::interval(heartbeat_interval);
-                    loop {
-                        interval.tick().await;
-                        let ping = TunnelMessage::Ping {
-                            timestamp: now_as_secs(),
-                        };
-                        if heartbeat_tx.send(ping).await.is_err() {
-                            break;
-                        }
-                    }
-                });
-
-                // Process incoming requests with shutdown check
-                loop {
-                    tokio::select! {
-                        _ = shutdown_rx.changed() => {
-                            heartbeat_handle.abort();
-                            break 'main_loop;
-                        }
-                        msg = rx.recv() => {
-                            match msg {
-                                Some(msg) => {
-                                    let response = handle_request(&config, &client, msg).await;
-
-                                    if let Some(res) = response
-                                        && tx.send(res).await.is_err()
-                                    {
-                                        error!("Failed to send response, connection may be closed");
-                                        break;
-                                    }
-                                }
-                                None => {
-                                    break;
-                                }
-                            }
-                        }
-                    }
-                }
+fn access_log_changed(current: &Config, new: &Config) -> bool {
+    current.access_log != new.access_log
+        || current.access_log_format != new.access_log_format
+        || current.access_log_max_size != new.access_log_max_size
+        || current.access_log_max_files != new.access_log_max_files
+}
 
-                heartbeat_handle.abort();
-                warn!("Connection to server lost");
-            }
+fn capture_changed(current: &Config, new: &Config) -> bool {
+    current.capture_file != new.capture_file
+        || current.capture_max_size != new.capture_max_size
+        || current.capture_max_files != new.capture_max_files
+}
+
+/// Parses the configuration file again and builds a new HTTP client for it.
+/// The access log and capture file are only reopened if their settings
+/// changed. An invalid configuration is rejected and `None` returned.
+async fn reload_config(config_file: &Path, current: &Context) -> Option<Context> {
+    let config = match parse_config(config_file.to_path_buf()).await {
+        Ok(config) => config,
+        Err(e) => {
+            error!(error = %e, "Invalid configuration, keeping the current one");
+            return None;
+        }
+    };
+    let access_log = if access_log_changed(&current.config, &config) {
+        open_access_log(&config).map(Arc::new)
+    } else {
+        Ok(current.access_log.clone())
+    };
+    let capture = if capture_changed(&current.config, &config) {
+        open_capture(&config)
+    } else {
+        Ok(current.capture.clone())
+    };
+    let (client, access_log, capture) =
+        match build_http_client(&config).and_then(|client| Ok((client, access_log?, capture?))) {
+            Ok(built) => built,
             Err(e) => {
-                error!("Failed to connect to server: {}", e);
+                error!(error = %e, "Invalid configuration, keeping the current one");
+                return None;
             }
-        }
+        };
+
+    Some(Context {
+        config: Arc::new(config),
+        client,
+        access_log,
+        capture,
+    })
+}
 
-        info!(
-            "Reconnecting in {} seconds...",
-            reconnect_interval.as_secs()
-        );
+/// Resolves on Ctrl+C or SIGTERM
+async fn shutdown_signal() {
+    let ctrl_c = async {
+        signal::ctrl_c()
+            .await
+            .expect("failed to install Ctrl+C handler");
+    };
 
-        // Check shutdown before reconnect sleep
+    #[cfg(unix)]
+    let terminate = async {
+        signal::unix::signal(signal::unix::SignalKind::terminate())
+            .expect("failed to install signal handler")
+            .recv()
+            .await;
+    };
+
+    #[cfg(not(unix))]
+    let terminate = std::future::pending::<()>();
+
+    tokio::select! {
+        _ = ctrl_c => {},
+        _ = terminate => {},
+    }
+
+    info!("Shutdown signal received, starting graceful shutdown");
+}
+
+#[tokio::main]
+async fn main() -> Result<()> {
+    let args = Args::parse();
+    if let Some(command) = args.command {
+        return run_command(command, args.config).await;
+    }
+
+    let config = Arc::new(parse_config(args.config.clone()).await?);
+
+    let (log_filter, _telemetry) = init_logging(
+        config.log_level,
+        &config.log_filter,
+        config.log_format,
+        config.otlp_endpoint.as_deref(),
+        "ha-tunnel-client",
+    )?;
+
+    info!(ha_server = %config.ha_server, ignore_ssl = %config.ha_ignore_ssl, servers = ?config.servers, server_mode = ?config.server_mode, pool_size = config.pool_size, "Starting Home Assistant Tunnel Client");
+    warn_weak_secret(&config.secret);
+
+    let mut client_id = client_id_of(&config);
+    let client = build_http_client(&config)?;
+    let access_log = open_access_log(&config)?;
+    let capture = open_capture(&config)?;
+    if let Some(path) = &config.capture_file {
+        warn!(path = %path, "Capture mode on, request and response bodies are written to disk");
+    }
+
+    // Shared with all tunnels, replaced on reload
+    let context = watch::Sender::new(Context {
+        config: config.clone(),
+        client,
+        access_log: Arc::new(access_log),
+        capture,
+    });
+
+    // Flips to true to drain and stop the current tunnels
+    let mut stop = watch::Sender::new(false);
+    let mut tunnels = JoinSet::new();
+    spawn_tunnels(&mut tunnels, &client_id, &config, &context, &stop);
+
+    // Reload on SIGHUP and config file changes
+    let mut reload_rx = watch_config(args.config.clone());
+
+    let shutdown = shutdown_signal();
+    tokio::pin!(shutdown);
+
+    loop {
         tokio::select! {
-            _ = shutdown_rx.changed() => {
-                break;
+            _ = &mut shutdown => break,
+            Some(()) = reload_rx.recv() => {
+                let current = context.borrow().clone();
+                let Some(new_context) = reload_config(&args.config, &current).await else {
+                    continue;
+                };
+
+                let reconnect = connection_changed(&context.borrow().config, &new_context.config);
+                let id_changed = context.borrow().config.client_id != new_context.config.client_id;
+                for (setting, changed) in [
+                    ("log_format", context.borrow().config.log_format != new_context.config.log_format),
+                    ("otlp_endpoint", context.borrow().config.otlp_endpoint != new_context.config.otlp_endpoint),
+                ] {
+                    if changed {
+                        warn!(setting = setting, "Setting changed, restart required to apply it");
+                    }
+                }
+                let new_config = new_context.config.clone();
+                if context.borrow().config.secret != new_config.secret {
+                    warn_weak_secret(&new_config.secret);
+                }
+                reload_log_filter(&log_filter, new_config.log_level, &new_config.log_filter);
+                context.send_replace(new_context);
+                info!(reconnect = reconnect, "Configuration reloaded");
+
+                if reconnect {
+                    if id_changed {
+                        client_id = client_id_of(&new_config);
+                    }
+
+                    // Old tunnels drain in the background while the new ones connect
+                    stop.send_replace(true);
+                    stop = watch::Sender::new(false);
+                    spawn_tunnels(&mut tunnels, &client_id, &new_config, &context, &stop);
+                }
             }
-            _ = sleep(reconnect_interval) => {}
+            // Stopped tunnels finished draining
+            Some(_) = tunnels.join_next() => {}
         }
     }
 
+    // Every tunnel bounds its drain by the grace period
+    stop.send_replace(true);
+    tunnels.join_all().await;
+
     info!("Client shut down gracefully");
 
     Ok(())
@@ -1,9 +1,19 @@
+use crate::auth::{
+    is_auth_flow_request, query_param, validate_login_flow_body, validate_oauth_client,
+    validate_token_request,
+};
+use crate::capture::{CaptureRecord, CapturedRequest, CapturedResponse};
 use crate::config::{Config, Features};
+use crate::connection::Context;
+use chrono::Utc;
+use common::access_log::AccessLogEntry;
+use common::compression::{Compression, compress_body, decompress_body};
 use common::error::ProxyError;
-use common::tunnel::TunnelMessage;
+use common::telemetry::{inject_headers, set_parent};
+use common::tunnel::{REQUEST_ID_HEADER, TunnelMessage};
 use reqwest::Client;
 use std::time::Instant;
-use tracing::{Instrument, debug, debug_span, error};
+use tracing::{Instrument, debug, error, info_span};
 
 fn validate_request(features: &Features, method: &str, path: &str) -> bool {
     (features.assistant_alexa && method == "POST" && path == "/api/alexa/smart_home")
@@ -14,6 +24,31 @@ fn validate_request(features: &Features, method: &str, path: &str) -> bool {
         || ((features.assistant_google || features.assistant_alexa)
             && method == "POST"
             && path == "/auth/token")
+        || ((features.assistant_google || features.assistant_alexa)
+            && features.auth_proxy
+            && is_auth_flow_request(method, path))
+}
+
+/// Checks the OAuth parameters of account linking requests before they reach
+/// Home Assistant
+fn validate_auth_request(
+    config: &Config,
+    method: &str,
+    path: &str,
+    query: Option<&str>,
+    headers: &[(String, String)],
+    body: Option<&[u8]>,
+) -> Result<(), ProxyError> {
+    match (method, path) {
+        ("GET", "/auth/authorize") => validate_oauth_client(
+            query_param(query, "client_id").as_deref(),
+            query_param(query, "redirect_uri").as_deref(),
+            &config.auth_redirect_hosts,
+        ),
+        ("POST", "/auth/login_flow") => validate_login_flow_body(body, &config.auth_redirect_hosts),
+        ("POST", "/auth/token") => validate_token_request(headers, body),
+        _ => Ok(()),
+    }
 }
 
 #[allow(clippy::too_many_arguments)]
@@ -23,7 +58,7 @@ async fn proxy_request(
     method: &str,
     path: &str,
     query: Option<String>,
-    headers: Vec<(String, String)>,
+    mut headers: Vec<(String, String)>,
     body: Option<Vec<u8>>,
     source_ip: Option<String>,
 ) -> Result<(u16, Vec<(String, String)>, Option<Vec<u8>>), ProxyError> {
@@ -47,6 +82,9 @@ async fn proxy_request(
         }
     };
 
+    let span = info_span!("ha_request", otel.kind = "client", method = %method, path = %path);
+    inject_headers(&span, &mut headers);
+
     for (name, value) in headers {
         request = request.header(&name, value);
     }
@@ -60,7 +98,7 @@ async fn proxy_request(
         request = request.body(body);
     }
 
-    let response = request.send().await?;
+    let response = request.send().instrument(span).await?;
     let status = response.status().as_u16();
     let response_headers: Vec<(String, String)> = response
         .headers()
@@ -82,6 +120,7 @@ async fn proxy_request(
 async fn handle_http_request(
     config: &Config,
     client: &Client,
+    compression: Option<Compression>,
     request_id: String,
     method: String,
     path: String,
@@ -99,8 +138,27 @@ async fn handle_http_request(
             status: 400,
             headers: vec![],
             body: Some("Feature not enabled!".bytes().collect()),
+            body_compression: None,
+            upstream_ms: None,
         }
-    } else if method == "GET" && path == "/auth/authorize" {
+    } else if let Err(e) = validate_auth_request(
+        config,
+        &method,
+        &path,
+        query.as_deref(),
+        &headers,
+        body.as_deref(),
+    ) {
+        debug!(error = %e, "Auth request rejected");
+        TunnelMessage::HttpResponse {
+            request_id,
+            status: 400,
+            headers: vec![],
+            body: Some(e.to_string().into_bytes()),
+            body_compression: None,
+            upstream_ms: None,
+        }
+    } else if method == "GET" && path == "/auth/authorize" && !config.features.auth_proxy {
         let redirect_url = format!(
             "{}{}?{}",
             config.ha_external_url.trim_end_matches('/'),
@@ -113,6 +171,8 @@ async fn handle_http_request(
             status: 307,
             headers: vec![("Location".to_string(), redirect_url)],
             body: None,
+            body_compression: None,
+            upstream_ms: None,
         }
     } else {
         let start = Instant::now();
@@ -129,17 +189,30 @@ async fn handle_http_request(
         .await
         {
             Ok((status, response_headers, response_body)) => {
-                let latency_ms = start.elapsed().as_millis();
+                let latency_ms = start.elapsed().as_millis() as u64;
                 debug!(
                     latency_ms = latency_ms,
                     status = status,
                     "Received response from Home Assistant"
                 );
+
+                // Bodies Home Assistant already encoded for the caller are passed through as-is
+                let already_encoded = response_headers
+                    .iter()
+                    .any(|(name, _)| name.eq_ignore_ascii_case("content-encoding"));
+                let (body, body_compression) = if already_encoded {
+                    (response_body, None)
+                } else {
+                    compress_body(compression, response_body)
+                };
+
                 TunnelMessage::HttpResponse {
                     request_id,
                     status,
                     headers: response_headers,
-                    body: response_body,
+                    body,
+                    body_compression,
+                    upstream_ms: Some(latency_ms),
                 }
             }
             Err(e) => {
@@ -155,9 +228,16 @@ async fn handle_http_request(
     }
 }
 
+/// Replaces the `X-Request-Id` header
+fn set_request_id(headers: &mut Vec<(String, String)>, request_id: &str) {
+    headers.retain(|(name, _)| !name.eq_ignore_ascii_case(REQUEST_ID_HEADER));
+    headers.push((REQUEST_ID_HEADER.to_string(), request_id.to_string()));
+}
+
 pub async fn handle_request(
-    config: &Config,
-    client: &Client,
+    ctx: &Context,
+    client_id: &str,
+    compression: Option<Compression>,
     msg: TunnelMessage,
 ) -> Option<TunnelMessage> {
     match msg {
@@ -166,18 +246,122 @@ pub async fn handle_request(
             method,
             path,
             query,
-            headers,
+            mut headers,
             body,
+            body_compression,
             source_ip,
+            correlation_id,
+            trace_context,
         } => {
-            let span = debug_span!("request", %request_id);
-            Some(
-                handle_http_request(
-                    config, client, request_id, method, path, query, headers, body, source_ip,
-                )
-                .instrument(span)
-                .await,
+            // Servers predating the correlation ID only send the per attempt ID
+            let correlation_id = correlation_id.unwrap_or_else(|| request_id.clone());
+            set_request_id(&mut headers, &correlation_id);
+
+            // Continues the trace of the server span
+            let span = info_span!(
+                "request",
+                request_id = %correlation_id,
+                method = %method,
+                path = %path,
+                otel.kind = "server",
+            );
+            set_parent(&span, &trace_context);
+            let body = match decompress_body(body_compression, body) {
+                Ok(body) => body,
+                Err(e) => {
+                    error!(request_id = %correlation_id, error = %e, "Failed to decompress request body");
+                    return Some(TunnelMessage::Error {
+                        request_id: Some(request_id),
+                        code: "invalid_body".to_string(),
+                        message: e.to_string(),
+                    });
+                }
+            };
+            let start = Instant::now();
+            let mut entry = AccessLogEntry {
+                timestamp: Utc::now(),
+                request_id: Some(correlation_id.clone()),
+                client_id: Some(client_id.to_string()),
+                source_ip: source_ip.clone(),
+                method: method.clone(),
+                path: path.clone(),
+                status: 0,
+                bytes: 0,
+                duration_ms: 0,
+                tunnel_ms: None,
+                upstream_ms: None,
+            };
+            // Only copied when capture mode is on
+            let captured_request = ctx.capture.as_ref().map(|_| {
+                CapturedRequest::new(&method, &path, query.as_deref(), &headers, body.as_deref())
+            });
+
+            let mut response = handle_http_request(
+                &ctx.config,
+                &ctx.client,
+                compression,
+                request_id,
+                method,
+                path,
+                query,
+                headers,
+                body,
+                source_ip,
             )
+            .instrument(span)
+            .await;
+
+            if let TunnelMessage::HttpResponse { headers, .. } = &mut response {
+                set_request_id(headers, &correlation_id);
+            }
+
+            if ctx.access_log.is_enabled() {
+                match &response {
+                    TunnelMessage::HttpResponse {
+                        status,
+                        body,
+                        upstream_ms,
+                        ..
+                    } => {
+                        entry.status = *status;
+                        entry.bytes = body.as_ref().map(|body| body.len()).unwrap_or_default();
+                        entry.upstream_ms = *upstream_ms;
+                    }
+                    _ => entry.status = 502,
+                }
+                entry.duration_ms = start.elapsed().as_millis() as u64;
+                ctx.access_log.log(&entry);
+            }
+
+            if let (Some(capture), Some(request)) = (&ctx.capture, captured_request) {
+                let (response, error) = match &response {
+                    TunnelMessage::HttpResponse {
+                        status,
+                        headers,
+                        body,
+                        body_compression,
+                        ..
+                    } => match decompress_body(*body_compression, body.clone()) {
+                        Ok(body) => (
+                            Some(CapturedResponse::new(*status, headers, body.as_deref())),
+                            None,
+                        ),
+                        Err(e) => (None, Some(e.to_string())),
+                    },
+                    TunnelMessage::Error { message, .. } => (None, Some(message.clone())),
+                    _ => (None, None),
+                };
+                capture.record(&CaptureRecord {
+                    timestamp: entry.timestamp,
+                    request_id: correlation_id,
+                    request,
+                    response,
+                    error,
+                    duration_ms: start.elapsed().as_millis() as u64,
+                });
+            }
+
+            Some(response)
         }
         TunnelMessage::Pong { timestamp: _ } => None,
         _ => {
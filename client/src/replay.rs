@@ -0,0 +1,261 @@
+use crate::capture::{CaptureRecord, CapturedRequest, REDACTED, content_type, is_form};
+use anyhow::{Context as _, Result, bail};
+use reqwest::{Client, Method};
+use std::path::Path;
+use std::time::Instant;
+use tokio::fs;
+use url::form_urlencoded;
+
+/// Headers set by the HTTP client itself
+const SKIPPED_HEADERS: [&str; 4] = ["host", "content-length", "connection", "transfer-encoding"];
+
+/// Sends the requests of a capture file to `ha_server` and prints the
+/// recorded next to the replayed status. `token` takes the place of the
+/// redacted credentials in headers, query strings and bodies. Fails if any
+/// status differs.
+pub async fn replay(
+    file: &Path,
+    ha_server: &str,
+    token: Option<&str>,
+    extra_headers: &[(String, String)],
+    ignore_ssl: bool,
+) -> Result<()> {
+    let content = fs::read_to_string(file)
+        .await
+        .with_context(|| format!("Failed to read {}", file.display()))?;
+    let client = Client::builder()
+        .danger_accept_invalid_certs(ignore_ssl)
+        .build()?;
+
+    let (mut replayed, mut mismatches) = (0, 0);
+    for (index, line) in content.lines().enumerate() {
+        if line.trim().is_empty() {
+            continue;
+        }
+        let record: CaptureRecord = serde_json::from_str(line)
+            .with_context(|| format!("Invalid capture record on line {}", index + 1))?;
+        let request = &record.request;
+
+        let url = request_url(ha_server, request, token);
+        let mut builder = client.request(Method::from_bytes(request.method.as_bytes())?, &url);
+        for (name, value) in &request.headers {
+            let skipped = SKIPPED_HEADERS
+                .iter()
+                .any(|header| name.eq_ignore_ascii_case(header));
+            if skipped
+                || extra_headers
+                    .iter()
+                    .any(|(n, _)| n.eq_ignore_ascii_case(name))
+            {
+                continue;
+            }
+            match (value.as_str(), token) {
+                (REDACTED, Some(token)) if name.eq_ignore_ascii_case("authorization") => {
+                    builder = builder.bearer_auth(token);
+                }
+                (REDACTED, _) => {}
+                _ => builder = builder.header(name, value),
+            }
+        }
+        for (name, value) in extra_headers {
+            builder = builder.header(name, value);
+        }
+        if let Some(body) = request_body(request, token)? {
+            builder = builder.body(body);
+        }
+
+        let recorded = record.response.as_ref().map(|response| response.status);
+        let start = Instant::now();
+        let result = builder.send().await;
+        let latency_ms = start.elapsed().as_millis();
+        replayed += 1;
+
+        let recorded_status = recorded
+            .map(|status| status.to_string())
+            .unwrap_or_else(|| "error".to_string());
+        match result {
+            Ok(response) => {
+                let status = response.status().as_u16();
+                let matches = recorded == Some(status);
+                if !matches {
+                    mismatches += 1;
+                }
+                println!(
+                    "{} {} {}: recorded {} ({} ms), replayed {} ({} ms){}",
+                    record.request_id,
+                    request.method,
+                    request.path,
+                    recorded_status,
+                    record.duration_ms,
+                    status,
+                    latency_ms,
+                    if matches { "" } else { " MISMATCH" }
+                );
+            }
+            Err(e) => {
+                mismatches += 1;
+                println!(
+                    "{} {} {}: recorded {} ({} ms), replay failed: {} MISMATCH",
+                    record.request_id,
+                    request.method,
+                    request.path,
+                    recorded_status,
+                    record.duration_ms,
+                    e
+                );
+            }
+        }
+    }
+
+    println!("{} requests replayed, {} mismatches", replayed, mismatches);
+    if mismatches > 0 {
+        bail!("{} of {} replayed requests differ", mismatches, replayed);
+    }
+    Ok(())
+}
+
+/// Puts `token` in place of the redacted values of a query string or form body
+fn restore_form(form: &str, token: &str) -> String {
+    form_urlencoded::Serializer::new(String::new())
+        .extend_pairs(form_urlencoded::parse(form.as_bytes()).map(|(key, value)| {
+            let value = if value == REDACTED {
+                token.into()
+            } else {
+                value
+            };
+            (key, value)
+        }))
+        .finish()
+}
+
+/// Puts `token` in place of the redacted string values of a JSON body
+fn restore_json(value: &mut serde_json::Value, token: &str) {
+    match value {
+        serde_json::Value::String(text) if text == REDACTED => *text = token.to_string(),
+        serde_json::Value::Object(map) => map
+            .values_mut()
+            .for_each(|value| restore_json(value, token)),
+        serde_json::Value::Array(values) => values
+            .iter_mut()
+            .for_each(|value| restore_json(value, token)),
+        _ => {}
+    }
+}
+
+fn request_url(ha_server: &str, request: &CapturedRequest, token: Option<&str>) -> String {
+    let query = match (&request.query, token) {
+        (Some(query), Some(token)) => format!("?{}", restore_form(query, token)),
+        (Some(query), None) => format!("?{}", query),
+        (None, _) => String::new(),
+    };
+    format!(
+        "{}{}{}",
+        ha_server.trim_end_matches('/'),
+        request.path,
+        query
+    )
+}
+
+fn request_body(request: &CapturedRequest, token: Option<&str>) -> Result<Option<Vec<u8>>> {
+    let Some(body) = &request.body else {
+        return Ok(None);
+    };
+    let body = body.to_bytes()?;
+    let Some(token) = token else {
+        return Ok(Some(body));
+    };
+
+    let body = match String::from_utf8(body) {
+        Ok(text) if is_form(content_type(&request.headers)) => {
+            restore_form(&text, token).into_bytes()
+        }
+        Ok(text) => match serde_json::from_str::<serde_json::Value>(&text) {
+            Ok(mut json) => {
+                restore_json(&mut json, token);
+                json.to_string().into_bytes()
+            }
+            Err(_) => text.replace(REDACTED, token).into_bytes(),
+        },
+        Err(e) => e.into_bytes(),
+    };
+    Ok(Some(body))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn request(query: Option<&str>, content_type: &str, body: Option<&[u8]>) -> CapturedRequest {
+        CapturedRequest::new(
+            "POST",
+            "/auth/token",
+            query,
+            &[("content-type".to_string(), content_type.to_string())],
+            body,
+        )
+    }
+
+    #[test]
+    fn test_restores_form_body() {
+        let request = request(
+            None,
+            "application/x-www-form-urlencoded",
+            Some(b"grant_type=refresh_token&refresh_token=abc&client_id=x"),
+        );
+
+        let body = request_body(&request, Some("new token")).unwrap().unwrap();
+        assert_eq!(
+            body,
+            b"grant_type=refresh_token&refresh_token=new+token&client_id=x"
+        );
+
+        let body = request_body(&request, None).unwrap().unwrap();
+        assert_eq!(
+            body,
+            b"grant_type=refresh_token&refresh_token=%5BREDACTED%5D&client_id=x"
+        );
+    }
+
+    #[test]
+    fn test_restores_json_body() {
+        let request = request(
+            None,
+            "application/json",
+            Some(br#"{"directive":{"payload":{"scope":{"token":"abc"}}}}"#),
+        );
+
+        let body = request_body(&request, Some("xyz")).unwrap().unwrap();
+        assert_eq!(
+            body,
+            br#"{"directive":{"payload":{"scope":{"token":"xyz"}}}}"#
+        );
+
+        // Tokens are escaped like any other JSON string
+        let body = request_body(&request, Some(r#"a"b\c"#)).unwrap().unwrap();
+        let json: serde_json::Value = serde_json::from_slice(&body).unwrap();
+        assert_eq!(json["directive"]["payload"]["scope"]["token"], r#"a"b\c"#);
+    }
+
+    #[test]
+    fn test_restores_text_body() {
+        let text = format!("token: {}", REDACTED);
+        let request = request(None, "text/plain", Some(text.as_bytes()));
+
+        let body = request_body(&request, Some("xyz")).unwrap().unwrap();
+        assert_eq!(body, b"token: xyz");
+    }
+
+    #[test]
+    fn test_restores_query() {
+        let request = request(Some("code=abc&state=1"), "text/plain", None);
+
+        assert_eq!(
+            request_url("http://ha:8123/", &request, Some("xyz")),
+            "http://ha:8123/auth/token?code=xyz&state=1"
+        );
+        assert_eq!(
+            request_url("http://ha:8123", &request, None),
+            "http://ha:8123/auth/token?code=%5BREDACTED%5D&state=1"
+        );
+    }
+}
@@ -1,3 +1,4 @@
+use common::compression::Compression;
 use common::error::ProxyError;
 use common::now_as_secs;
 use common::tunnel::{TunnelMessage, generate_auth_signature};
@@ -10,7 +11,17 @@ pub async fn connect(
     client_id: &str,
     server: &str,
     secret: &str,
-) -> Result<(mpsc::Sender<TunnelMessage>, mpsc::Receiver<TunnelMessage>), ProxyError> {
+    compression: &[Compression],
+    priority: u32,
+    weight: u32,
+) -> Result<
+    (
+        mpsc::Sender<TunnelMessage>,
+        mpsc::Receiver<TunnelMessage>,
+        Option<Compression>,
+    ),
+    ProxyError,
+> {
     let server_url = format!("{}/tunnel", server);
     info!(url = %server_url, client_id = %client_id, "Connecting to server");
 
@@ -28,6 +39,10 @@ pub async fn connect(
         client_id: client_id.to_string(),
         timestamp,
         signature,
+        compression: compression.to_vec(),
+        priority,
+        weight,
+        version: Some(env!("CARGO_PKG_VERSION").to_string()),
     };
 
     write
@@ -36,18 +51,23 @@ pub async fn connect(
         .map_err(|e| ProxyError::Connection(e.to_string()))?;
 
     // Wait for auth response
-    if let Some(msg) = read.next().await {
+    let compression = if let Some(msg) = read.next().await {
         let msg = msg.map_err(|e| ProxyError::Connection(e.to_string()))?;
         let response = TunnelMessage::from_ws_message(msg)?;
 
         match response {
-            TunnelMessage::AuthResponse { success, message } => {
+            TunnelMessage::AuthResponse {
+                success,
+                message,
+                compression,
+            } => {
                 if !success {
                     return Err(ProxyError::AuthFailed(
                         message.unwrap_or_else(|| "Unknown error".to_string()),
                     ));
                 }
-                info!("Authentication successful");
+                info!(compression = ?compression, "Authentication successful");
+                compression
             }
             _ => {
                 return Err(ProxyError::AuthFailed("Unexpected response".to_string()));
@@ -55,7 +75,7 @@ pub async fn connect(
         }
     } else {
         return Err(ProxyError::Connection("No auth response".to_string()));
-    }
+    };
 
     // Create channels
     let (outbound_tx, mut outbound_rx) = mpsc::channel::<TunnelMessage>(100);
@@ -109,5 +129,5 @@ pub async fn connect(
         debug!("Reader task ended");
     });
 
-    Ok((outbound_tx, inbound_rx))
+    Ok((outbound_tx, inbound_rx, compression))
 }
@@ -0,0 +1,216 @@
+use crate::error::ProxyError;
+use crate::line_writer::LineWriter;
+use crate::rotating_file::RotatingFile;
+use chrono::{DateTime, Utc};
+use serde::Serialize;
+use std::io::{self, Write};
+use std::path::Path;
+use std::str::FromStr;
+
+/// Line format of the access log
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum AccessLogFormat {
+    #[default]
+    Json,
+    /// Common Log Format, followed by request ID and latencies
+    Common,
+    Logfmt,
+}
+
+impl FromStr for AccessLogFormat {
+    type Err = ProxyError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "json" => Ok(AccessLogFormat::Json),
+            "clf" | "common" => Ok(AccessLogFormat::Common),
+            "logfmt" => Ok(AccessLogFormat::Logfmt),
+            other => Err(ProxyError::Config(format!(
+                "Unknown access log format: {}",
+                other
+            ))),
+        }
+    }
+}
+
+/// One line of the access log. The query string is left out as it may carry
+/// OAuth codes and tokens.
+#[derive(Debug, Clone, Serialize)]
+pub struct AccessLogEntry {
+    pub timestamp: DateTime<Utc>,
+    pub request_id: Option<String>,
+    pub client_id: Option<String>,
+    pub source_ip: Option<String>,
+    pub method: String,
+    pub path: String,
+    pub status: u16,
+    /// Size of the response body as sent, compressed on the client side if
+    /// tunnel compression is used
+    pub bytes: usize,
+    /// Total time spent on the request
+    pub duration_ms: u64,
+    /// Time from forwarding the request into the tunnel until its response arrived
+    pub tunnel_ms: Option<u64>,
+    /// Time Home Assistant took to answer
+    pub upstream_ms: Option<u64>,
+}
+
+impl AccessLogEntry {
+    pub fn format(&self, format: AccessLogFormat) -> String {
+        match format {
+            AccessLogFormat::Json => serde_json::to_string(self).unwrap_or_default(),
+            AccessLogFormat::Common => format!(
+                "{} - {} [{}] \"{} {} HTTP/1.1\" {} {} {} {} {} {}",
+                or_dash(&self.source_ip),
+                or_dash(&self.client_id),
+                self.timestamp.format("%d/%b/%Y:%H:%M:%S %z"),
+                self.method,
+                self.path,
+                self.status,
+                if self.bytes == 0 {
+                    "-".to_string()
+                } else {
+                    self.bytes.to_string()
+                },
+                or_dash(&self.request_id),
+                self.duration_ms,
+                or_dash(&self.tunnel_ms),
+                or_dash(&self.upstream_ms),
+            ),
+            AccessLogFormat::Logfmt => {
+                let fields = [
+                    ("time", Some(self.timestamp.to_rfc3339())),
+                    ("request_id", self.request_id.clone()),
+                    ("client_id", self.client_id.clone()),
+                    ("source_ip", self.source_ip.clone()),
+                    ("method", Some(self.method.clone())),
+                    ("path", Some(self.path.clone())),
+                    ("status", Some(self.status.to_string())),
+                    ("bytes", Some(self.bytes.to_string())),
+                    ("duration_ms", Some(self.duration_ms.to_string())),
+                    ("tunnel_ms", self.tunnel_ms.map(|ms| ms.to_string())),
+                    ("upstream_ms", self.upstream_ms.map(|ms| ms.to_string())),
+                ];
+                fields
+                    .iter()
+                    .filter_map(|(key, value)| {
+                        value
+                            .as_deref()
+                            .map(|value| format!("{}={}", key, logfmt_value(value)))
+                    })
+                    .collect::<Vec<_>>()
+                    .join(" ")
+            }
+        }
+    }
+}
+
+fn or_dash<T: ToString>(value: &Option<T>) -> String {
+    value
+        .as_ref()
+        .map(|value| value.to_string())
+        .unwrap_or_else(|| "-".to_string())
+}
+
+fn logfmt_value(value: &str) -> String {
+    if value.is_empty() || value.contains([' ', '=', '"']) {
+        format!("{:?}", value)
+    } else {
+        value.to_string()
+    }
+}
+
+/// Access log with one line per request, written to stdout or a rotating file
+pub struct AccessLog {
+    format: AccessLogFormat,
+    /// None if disabled
+    output: Option<LineWriter>,
+}
+
+impl AccessLog {
+    /// Opens the access log for `target`, which is `stdout` or a file path
+    /// (None = disabled). `max_size` is in bytes.
+    pub fn open(
+        target: Option<&str>,
+        format: AccessLogFormat,
+        max_size: u64,
+        max_files: usize,
+    ) -> Result<Self, ProxyError> {
+        let output = match target {
+            None => None,
+            Some("stdout") => Some(LineWriter::spawn("access log", |line| {
+                writeln!(io::stdout().lock(), "{}", line)
+            })),
+            Some(path) => {
+                let mut file =
+                    RotatingFile::open(Path::new(path), max_size, max_files).map_err(|e| {
+                        ProxyError::Config(format!("Failed to open access log {}: {}", path, e))
+                    })?;
+                Some(LineWriter::spawn("access log", move |line| {
+                    file.write_line(line)
+                }))
+            }
+        };
+        let output = output
+            .transpose()
+            .map_err(|e| ProxyError::Config(format!("Failed to start access log writer: {}", e)))?;
+
+        Ok(Self { format, output })
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        self.output.is_some()
+    }
+
+    pub fn log(&self, entry: &AccessLogEntry) {
+        if let Some(output) = &self.output {
+            output.write_line(entry.format(self.format));
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::TimeZone;
+
+    fn entry() -> AccessLogEntry {
+        AccessLogEntry {
+            timestamp: Utc.with_ymd_and_hms(2025, 3, 1, 12, 30, 5).unwrap(),
+            request_id: Some("abc".to_string()),
+            client_id: Some("home".to_string()),
+            source_ip: Some("1.2.3.4".to_string()),
+            method: "POST".to_string(),
+            path: "/api/alexa".to_string(),
+            status: 200,
+            bytes: 42,
+            duration_ms: 15,
+            tunnel_ms: Some(12),
+            upstream_ms: None,
+        }
+    }
+
+    #[test]
+    fn test_formats() {
+        assert_eq!(
+            entry().format(AccessLogFormat::Common),
+            "1.2.3.4 - home [01/Mar/2025:12:30:05 +0000] \"POST /api/alexa HTTP/1.1\" 200 42 abc 15 12 -"
+        );
+        assert_eq!(
+            entry().format(AccessLogFormat::Logfmt),
+            "time=2025-03-01T12:30:05+00:00 request_id=abc client_id=home source_ip=1.2.3.4 method=POST path=/api/alexa status=200 bytes=42 duration_ms=15 tunnel_ms=12"
+        );
+
+        let json: serde_json::Value =
+            serde_json::from_str(&entry().format(AccessLogFormat::Json)).unwrap();
+        assert_eq!(json["status"], 200);
+        assert_eq!(json["upstream_ms"], serde_json::Value::Null);
+    }
+
+    #[test]
+    fn test_logfmt_quotes_values() {
+        assert_eq!(logfmt_value("/a b"), "\"/a b\"");
+        assert_eq!(logfmt_value(""), "\"\"");
+        assert_eq!(logfmt_value("/a"), "/a");
+    }
+}
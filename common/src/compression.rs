@@ -0,0 +1,166 @@
+use crate::error::ProxyError;
+use crate::tunnel::MAX_BODY_SIZE;
+use flate2::Compression as GzipLevel;
+use flate2::read::{GzDecoder, GzEncoder};
+use serde::{Deserialize, Serialize};
+use std::io::Read;
+use std::str::FromStr;
+
+/// Bodies smaller than this are sent uncompressed
+pub const MIN_COMPRESS_SIZE: usize = 1024;
+
+/// Body compression negotiated between client and server during authentication
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Compression {
+    Gzip,
+    Zstd,
+}
+
+impl Compression {
+    /// HTTP `Content-Encoding` token of this compression
+    pub fn encoding(&self) -> &'static str {
+        match self {
+            Compression::Gzip => "gzip",
+            Compression::Zstd => "zstd",
+        }
+    }
+
+    pub fn compress(&self, data: &[u8]) -> Result<Vec<u8>, ProxyError> {
+        let mut out = vec![];
+        match self {
+            Compression::Gzip => {
+                GzEncoder::new(data, GzipLevel::default()).read_to_end(&mut out)?;
+            }
+            Compression::Zstd => {
+                out = zstd::encode_all(data, 0)?;
+            }
+        }
+        Ok(out)
+    }
+
+    /// Fails if the decompressed body exceeds `MAX_BODY_SIZE`
+    pub fn decompress(&self, data: &[u8]) -> Result<Vec<u8>, ProxyError> {
+        let limit = MAX_BODY_SIZE as u64 + 1;
+        let mut out = vec![];
+        match self {
+            Compression::Gzip => {
+                GzDecoder::new(data).take(limit).read_to_end(&mut out)?;
+            }
+            Compression::Zstd => {
+                zstd::Decoder::new(data)?
+                    .take(limit)
+                    .read_to_end(&mut out)?;
+            }
+        }
+        if out.len() > MAX_BODY_SIZE {
+            return Err(ProxyError::InvalidRequest(format!(
+                "Decompressed body exceeds {} bytes",
+                MAX_BODY_SIZE
+            )));
+        }
+        Ok(out)
+    }
+}
+
+impl FromStr for Compression {
+    type Err = ProxyError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "gzip" => Ok(Compression::Gzip),
+            "zstd" => Ok(Compression::Zstd),
+            other => Err(ProxyError::Config(format!(
+                "Unknown compression: {}",
+                other
+            ))),
+        }
+    }
+}
+
+/// Picks the first compression offered by the client that the server supports
+pub fn negotiate(offered: &[Compression], supported: &[Compression]) -> Option<Compression> {
+    offered.iter().find(|c| supported.contains(c)).copied()
+}
+
+/// Compresses a body for the tunnel if compression was negotiated and the body
+/// is large enough to benefit from it. Returns the body together with the
+/// compression that was actually applied.
+pub fn compress_body(
+    compression: Option<Compression>,
+    body: Option<Vec<u8>>,
+) -> (Option<Vec<u8>>, Option<Compression>) {
+    match (compression, body) {
+        (Some(compression), Some(body)) if body.len() >= MIN_COMPRESS_SIZE => {
+            match compression.compress(&body) {
+                Ok(compressed) if compressed.len() < body.len() => {
+                    (Some(compressed), Some(compression))
+                }
+                _ => (Some(body), None),
+            }
+        }
+        (_, body) => (body, None),
+    }
+}
+
+/// Reverses `compress_body`
+pub fn decompress_body(
+    compression: Option<Compression>,
+    body: Option<Vec<u8>>,
+) -> Result<Option<Vec<u8>>, ProxyError> {
+    match (compression, body) {
+        (Some(compression), Some(body)) => Ok(Some(compression.decompress(&body)?)),
+        (_, body) => Ok(body),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_roundtrip() {
+        let body = b"hello world ".repeat(200);
+        for compression in [Compression::Gzip, Compression::Zstd] {
+            let (compressed, applied) = compress_body(Some(compression), Some(body.clone()));
+            assert_eq!(applied, Some(compression));
+            assert!(compressed.as_ref().unwrap().len() < body.len());
+            assert_eq!(
+                decompress_body(applied, compressed).unwrap(),
+                Some(body.clone())
+            );
+        }
+    }
+
+    #[test]
+    fn test_decompression_is_limited() {
+        let body = vec![0; MAX_BODY_SIZE + 1];
+        for compression in [Compression::Gzip, Compression::Zstd] {
+            let compressed = compression.compress(&body).unwrap();
+            assert!(compression.decompress(&compressed).is_err());
+            assert!(
+                compression
+                    .decompress(&compression.compress(&body[1..]).unwrap())
+                    .is_ok()
+            );
+        }
+    }
+
+    #[test]
+    fn test_small_bodies_stay_uncompressed() {
+        let (body, applied) = compress_body(Some(Compression::Zstd), Some(b"{}".to_vec()));
+        assert_eq!(body, Some(b"{}".to_vec()));
+        assert_eq!(applied, None);
+    }
+
+    #[test]
+    fn test_negotiate() {
+        let supported = [Compression::Gzip];
+        assert_eq!(
+            negotiate(&[Compression::Zstd, Compression::Gzip], &supported),
+            Some(Compression::Gzip)
+        );
+        assert_eq!(negotiate(&[Compression::Zstd], &supported), None);
+        assert_eq!(negotiate(&[], &supported), None);
+    }
+}
@@ -23,8 +23,8 @@ pub enum ProxyError {
     #[error("Authentication failed: {0}")]
     AuthFailed(String),
 
-    #[error("Rate limited")]
-    RateLimited,
+    #[error("Rate limited, retry after {retry_after}s")]
+    RateLimited { retry_after: u64 },
 
     #[error("IO error: {0}")]
     Io(#[from] std::io::Error),
@@ -44,7 +44,14 @@ impl From<ProxyError> for axum::response::Response {
         let (status, message) = match &err {
             ProxyError::FilterDenied(_) => (StatusCode::FORBIDDEN, err.to_string()),
             ProxyError::AuthFailed(_) => (StatusCode::UNAUTHORIZED, err.to_string()),
-            ProxyError::RateLimited => (StatusCode::TOO_MANY_REQUESTS, err.to_string()),
+            ProxyError::RateLimited { retry_after } => {
+                return (
+                    StatusCode::TOO_MANY_REQUESTS,
+                    [(axum::http::header::RETRY_AFTER, retry_after.to_string())],
+                    err.to_string(),
+                )
+                    .into_response();
+            }
             ProxyError::InvalidRequest(_) => (StatusCode::BAD_REQUEST, err.to_string()),
             _ => (
                 StatusCode::INTERNAL_SERVER_ERROR,
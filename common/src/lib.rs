@@ -1,6 +1,15 @@
 use std::time::{SystemTime, UNIX_EPOCH};
 
+pub mod access_log;
+pub mod compression;
 pub mod error;
+pub mod line_writer;
+pub mod logging;
+pub mod reload;
+pub mod report;
+pub mod rotating_file;
+pub mod secrets;
+pub mod telemetry;
 pub mod tunnel;
 
 pub fn now_as_secs() -> u64 {
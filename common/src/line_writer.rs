@@ -0,0 +1,72 @@
+use std::io;
+use std::sync::mpsc::{self, SyncSender, TrySendError};
+use std::thread;
+use tracing::{error, warn};
+
+/// Lines waiting to be written before further lines are dropped
+const QUEUE_SIZE: usize = 1024;
+
+/// Hands lines to a dedicated thread that writes them, so request handlers
+/// never block on the file system. The thread exits once the writer is
+/// dropped and the queued lines are written.
+pub struct LineWriter {
+    name: &'static str,
+    sender: SyncSender<String>,
+}
+
+impl LineWriter {
+    /// Spawns the thread calling `write` for every line. `name` identifies
+    /// the output in log messages.
+    pub fn spawn<W>(name: &'static str, mut write: W) -> io::Result<Self>
+    where
+        W: FnMut(&str) -> io::Result<()> + Send + 'static,
+    {
+        let (sender, receiver) = mpsc::sync_channel::<String>(QUEUE_SIZE);
+        thread::Builder::new()
+            .name(name.replace(' ', "-"))
+            .spawn(move || {
+                for line in receiver {
+                    if let Err(e) = write(&line) {
+                        error!(error = %e, output = name, "Failed to write line");
+                    }
+                }
+            })?;
+
+        Ok(Self { name, sender })
+    }
+
+    /// Queues a line, dropping it if the writer thread falls behind
+    pub fn write_line(&self, line: String) {
+        match self.sender.try_send(line) {
+            Ok(()) => {}
+            Err(TrySendError::Full(_)) => {
+                warn!(output = self.name, "Writer falling behind, line dropped");
+            }
+            Err(TrySendError::Disconnected(_)) => {
+                error!(output = self.name, "Writer thread stopped, line dropped");
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_writes_lines_in_order() {
+        let (tx, rx) = mpsc::channel();
+        let writer = LineWriter::spawn("test output", move |line| {
+            tx.send(line.to_string()).unwrap();
+            Ok(())
+        })
+        .unwrap();
+
+        writer.write_line("first".to_string());
+        writer.write_line("second".to_string());
+        drop(writer);
+
+        let lines: Vec<String> = rx.iter().collect();
+        assert_eq!(lines, ["first", "second"]);
+    }
+}
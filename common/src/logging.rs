@@ -0,0 +1,120 @@
+use crate::error::ProxyError;
+use crate::telemetry::{TelemetryGuard, init_tracer};
+use std::str::FromStr;
+use tracing::level_filters::LevelFilter;
+use tracing::{Level, warn};
+use tracing_subscriber::filter::Targets;
+use tracing_subscriber::layer::SubscriberExt;
+use tracing_subscriber::util::SubscriberInitExt;
+use tracing_subscriber::{EnvFilter, Layer, Registry, fmt, reload};
+
+/// Crates whose spans are exported over OTLP, whatever the log filter is
+const TRACED_TARGETS: [&str; 3] = ["ha_tunnel_server", "ha_tunnel_client", "common"];
+
+/// Changes the log filter at runtime
+pub type LogFilterHandle = reload::Handle<EnvFilter, Registry>;
+
+/// Output format of the application log
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum LogFormat {
+    /// Human readable, one line per event
+    #[default]
+    Full,
+    /// Shorter single line output
+    Compact,
+    /// Multi line output for development
+    Pretty,
+    /// One JSON object per line for log pipelines
+    Json,
+}
+
+impl FromStr for LogFormat {
+    type Err = ProxyError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "full" => Ok(LogFormat::Full),
+            "compact" => Ok(LogFormat::Compact),
+            "pretty" => Ok(LogFormat::Pretty),
+            "json" => Ok(LogFormat::Json),
+            other => Err(ProxyError::Config(format!("Unknown log format: {}", other))),
+        }
+    }
+}
+
+/// Builds the log filter from `level` for everything and `directives` in
+/// `EnvFilter` syntax (e.g. `ha_tunnel_server::proxy=debug,tower_http=warn`)
+/// overriding it for single targets
+pub fn build_filter(level: Level, directives: &str) -> Result<EnvFilter, ProxyError> {
+    let filter = EnvFilter::builder()
+        .parse(directives)
+        .map_err(|e| ProxyError::Config(format!("Invalid log filter: {}", e)))?;
+    Ok(filter.add_directive(LevelFilter::from_level(level).into()))
+}
+
+/// Installs the global subscriber, exporting spans over OTLP if an
+/// `otlp_endpoint` is given. The filter only applies to the log output and
+/// sits in a reload layer so it can change at runtime. Exported are the
+/// spans of this project at INFO and above, independent of the log level.
+pub fn init_logging(
+    level: Level,
+    directives: &str,
+    format: LogFormat,
+    otlp_endpoint: Option<&str>,
+    service_name: &'static str,
+) -> Result<(LogFilterHandle, TelemetryGuard), ProxyError> {
+    let (filter, handle) = reload::Layer::new(build_filter(level, directives)?);
+
+    let (tracer, guard) = match otlp_endpoint {
+        Some(endpoint) => {
+            let (tracer, guard) = init_tracer(endpoint, service_name)?;
+            (Some(tracer), guard)
+        }
+        None => (None, TelemetryGuard::default()),
+    };
+
+    let output = match format {
+        LogFormat::Full => fmt::layer().with_target(false).boxed(),
+        LogFormat::Compact => fmt::layer().compact().with_target(false).boxed(),
+        LogFormat::Pretty => fmt::layer().pretty().boxed(),
+        LogFormat::Json => fmt::layer().json().flatten_event(true).boxed(),
+    };
+
+    let traced = Targets::new().with_targets(TRACED_TARGETS.map(|target| (target, Level::INFO)));
+    tracing_subscriber::registry()
+        .with(output.with_filter(filter))
+        .with(tracer.map(|tracer| {
+            tracing_opentelemetry::layer()
+                .with_tracer(tracer)
+                .with_filter(traced)
+        }))
+        .init();
+
+    Ok((handle, guard))
+}
+
+/// Swaps in the filter of a reloaded configuration
+pub fn reload_log_filter(handle: &LogFilterHandle, level: Level, directives: &str) {
+    let result = build_filter(level, directives).and_then(|filter| {
+        handle
+            .reload(filter)
+            .map_err(|e| ProxyError::Config(e.to_string()))
+    });
+    if let Err(e) = result {
+        warn!(error = %e, "Failed to change log filter");
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_build_filter() {
+        let filter = build_filter(Level::WARN, "ha_tunnel_server::proxy=debug").unwrap();
+        assert_eq!(filter.to_string(), "ha_tunnel_server::proxy=debug,warn");
+
+        assert!(build_filter(Level::INFO, "proxy=loud").is_err());
+        assert!("yaml".parse::<LogFormat>().is_err());
+    }
+}
@@ -0,0 +1,64 @@
+use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime};
+use tokio::sync::mpsc;
+use tracing::{debug, info};
+
+/// How often the config file is checked for modifications
+pub const WATCH_INTERVAL: Duration = Duration::from_secs(2);
+
+/// Spawns a task that signals the returned receiver whenever the process
+/// receives SIGHUP or the modification time of `config_file` changes
+pub fn watch_config(config_file: PathBuf) -> mpsc::Receiver<()> {
+    let (tx, rx) = mpsc::channel(1);
+
+    tokio::spawn(async move {
+        #[cfg(unix)]
+        let mut hangup = tokio::signal::unix::signal(tokio::signal::unix::SignalKind::hangup())
+            .expect("failed to install SIGHUP handler");
+        #[cfg(not(unix))]
+        let mut hangup = ();
+
+        let mut modified = modified_time(&config_file).await;
+        let mut interval = tokio::time::interval(WATCH_INTERVAL);
+
+        loop {
+            tokio::select! {
+                _ = recv_hangup(&mut hangup) => {
+                    info!("SIGHUP received, reloading configuration");
+                }
+                _ = interval.tick() => {
+                    let current = modified_time(&config_file).await;
+                    if current == modified {
+                        continue;
+                    }
+                    modified = current;
+                    info!(file = %config_file.display(), "Configuration file changed, reloading");
+                }
+            }
+
+            // A reload that is still queued picks up this change as well
+            if tx.is_closed() {
+                break;
+            }
+            if tx.try_send(()).is_err() {
+                debug!("Reload already pending");
+            }
+        }
+    });
+
+    rx
+}
+
+async fn modified_time(path: &Path) -> Option<SystemTime> {
+    tokio::fs::metadata(path).await.ok()?.modified().ok()
+}
+
+#[cfg(unix)]
+async fn recv_hangup(signal: &mut tokio::signal::unix::Signal) {
+    signal.recv().await;
+}
+
+#[cfg(not(unix))]
+async fn recv_hangup(_: &mut ()) {
+    std::future::pending::<()>().await;
+}
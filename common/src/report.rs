@@ -0,0 +1,65 @@
+use anyhow::{Result, bail};
+
+/// Prints the outcome of the checks of a subcommand
+#[derive(Default)]
+pub struct Report {
+    failures: usize,
+}
+
+impl Report {
+    pub fn info(&self, message: impl AsRef<str>) {
+        println!("       {}", message.as_ref());
+    }
+
+    pub fn ok(&self, message: impl AsRef<str>) {
+        println!("[ok]   {}", message.as_ref());
+    }
+
+    pub fn warn(&self, message: impl AsRef<str>) {
+        println!("[warn] {}", message.as_ref());
+    }
+
+    pub fn fail(&mut self, message: impl AsRef<str>) {
+        self.failures += 1;
+        println!("[fail] {}", message.as_ref());
+    }
+
+    /// Fails, or only warns if the problem doesn't matter with the current configuration
+    pub fn problem(&mut self, fail: bool, message: impl AsRef<str>) {
+        if fail {
+            self.fail(message)
+        } else {
+            self.warn(message)
+        }
+    }
+
+    /// Errors if any check failed, so the command exits with a non-zero status
+    pub fn finish(self) -> Result<()> {
+        if self.failures > 0 {
+            bail!("{} check(s) failed", self.failures);
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_finish_fails_on_failed_checks() {
+        let mut report = Report::default();
+        report.ok("fine");
+        report.warn("questionable");
+        report.problem(false, "harmless here");
+        assert!(report.finish().is_ok());
+
+        let mut report = Report::default();
+        report.fail("broken");
+        report.problem(true, "also broken");
+        assert_eq!(
+            report.finish().unwrap_err().to_string(),
+            "2 check(s) failed"
+        );
+    }
+}
@@ -0,0 +1,131 @@
+use crate::error::ProxyError;
+use config::{Config, Source};
+use std::collections::HashSet;
+use tracing::warn;
+
+/// Length below which a secret is considered weak
+pub const MIN_SECRET_LENGTH: usize = 16;
+
+/// Distinct characters below which a secret is considered weak
+const MIN_DISTINCT_CHARACTERS: usize = 8;
+
+/// Values from the sample configurations and documentation
+const SAMPLE_SECRETS: [&str; 6] = [
+    "hello-world",
+    "your-secure-secret",
+    "changeme",
+    "change-me",
+    "secret",
+    "password",
+];
+
+/// Reads every top-level `<key>_file` setting (e.g. `secret_file` or
+/// `HA_TUNNEL_SECRET_FILE`) as the path of a file holding the value of
+/// `<key>`, which it takes precedence over. Surrounding whitespace of the
+/// file content is stripped. `path_keys` are settings that end in `_file`
+/// but are paths themselves.
+pub fn resolve_file_keys(settings: Config, path_keys: &[&str]) -> Result<Config, ProxyError> {
+    let values = settings
+        .collect()
+        .map_err(|e| ProxyError::Config(e.to_string()))?;
+
+    let mut builder = Config::builder().add_source(settings);
+    for (key, value) in values {
+        let Some(base) = key.strip_suffix("_file") else {
+            continue;
+        };
+        if path_keys.contains(&key.as_str()) {
+            continue;
+        }
+        let path = value
+            .into_string()
+            .map_err(|e| ProxyError::Config(format!("Invalid {}: {}", key, e)))?;
+        if path.is_empty() {
+            continue;
+        }
+
+        let content = std::fs::read_to_string(&path).map_err(|e| {
+            ProxyError::Config(format!("Failed to read {} from {}: {}", base, path, e))
+        })?;
+        builder = builder
+            .set_override(base, content.trim().to_string())
+            .map_err(|e| ProxyError::Config(e.to_string()))?;
+    }
+
+    builder
+        .build()
+        .map_err(|e| ProxyError::Config(e.to_string()))
+}
+
+/// Why `secret` is easy to guess, if it is
+pub fn weak_secret(secret: &str) -> Option<String> {
+    let distinct = secret.chars().collect::<HashSet<_>>().len();
+
+    if SAMPLE_SECRETS
+        .iter()
+        .any(|sample| secret.eq_ignore_ascii_case(sample))
+    {
+        Some("it is a sample value".to_string())
+    } else if secret.chars().count() < MIN_SECRET_LENGTH {
+        Some(format!(
+            "it is shorter than {} characters",
+            MIN_SECRET_LENGTH
+        ))
+    } else if distinct < MIN_DISTINCT_CHARACTERS {
+        Some("it has too few distinct characters".to_string())
+    } else {
+        None
+    }
+}
+
+/// Logs a warning if `secret` is weak
+pub fn warn_weak_secret(secret: &str) {
+    if let Some(reason) = weak_secret(secret) {
+        warn!(
+            reason = %reason,
+            "Weak secret, generate a random one with `ha-tunnel-server gen-secret`"
+        );
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_weak_secret() {
+        assert!(weak_secret("hello-world").is_some());
+        assert!(weak_secret("Hello-World").is_some());
+        assert!(weak_secret("abc123").is_some());
+        assert!(weak_secret("aaaaaaaaaaaaaaaaaaaaaaaa").is_some());
+        assert!(weak_secret("4f1c9a0e7d2b8c6f3e5a1d9b").is_none());
+    }
+
+    #[test]
+    fn test_resolve_file_keys() {
+        let path = std::env::temp_dir().join(format!("ha-tunnel-secret-{}", std::process::id()));
+        std::fs::write(&path, "from-file\n").unwrap();
+
+        let settings = Config::builder()
+            .set_default("secret", "from-config")
+            .unwrap()
+            .set_override("secret_file", path.to_str().unwrap())
+            .unwrap()
+            .set_override("capture_file", "/tmp/capture.jsonl")
+            .unwrap()
+            .build()
+            .unwrap();
+        let settings = resolve_file_keys(settings, &["capture_file"]).unwrap();
+        std::fs::remove_file(&path).unwrap();
+
+        assert_eq!(settings.get_string("secret").unwrap(), "from-file");
+        assert!(settings.get_string("capture").is_err());
+
+        let missing = Config::builder()
+            .set_override("secret_file", "/nonexistent/secret")
+            .unwrap()
+            .build()
+            .unwrap();
+        assert!(resolve_file_keys(missing, &[]).is_err());
+    }
+}
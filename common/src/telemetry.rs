@@ -0,0 +1,217 @@
+use crate::error::ProxyError;
+use axum::http::HeaderMap;
+use opentelemetry::propagation::{Extractor, Injector};
+use opentelemetry::trace::{TraceContextExt, TracerProvider};
+use opentelemetry::{Context, global};
+use opentelemetry_otlp::{SpanExporter, WithExportConfig};
+use opentelemetry_sdk::Resource;
+use opentelemetry_sdk::propagation::TraceContextPropagator;
+use opentelemetry_sdk::trace::{SdkTracer, SdkTracerProvider};
+use std::collections::HashMap;
+use tracing::{Span, warn};
+use tracing_opentelemetry::OpenTelemetrySpanExt;
+
+/// Headers of the W3C trace context, replaced when a request is passed on
+const TRACE_CONTEXT_HEADERS: [&str; 2] = ["traceparent", "tracestate"];
+
+/// Flushes the spans not exported yet when dropped
+#[derive(Default)]
+pub struct TelemetryGuard {
+    provider: Option<SdkTracerProvider>,
+}
+
+impl Drop for TelemetryGuard {
+    fn drop(&mut self) {
+        if let Some(provider) = self.provider.take()
+            && let Err(e) = provider.shutdown()
+        {
+            warn!(error = %e, "Failed to flush traces");
+        }
+    }
+}
+
+/// Sets up the export of spans over OTLP/HTTP to `endpoint` (the collector
+/// base URL, e.g. `http://localhost:4318`). Returns the tracer for the
+/// tracing layer and the guard flushing it on shutdown.
+pub fn init_tracer(
+    endpoint: &str,
+    service_name: &'static str,
+) -> Result<(SdkTracer, TelemetryGuard), ProxyError> {
+    let endpoint = if endpoint.ends_with("/v1/traces") {
+        endpoint.to_string()
+    } else {
+        format!("{}/v1/traces", endpoint.trim_end_matches('/'))
+    };
+    let exporter = SpanExporter::builder()
+        .with_http()
+        .with_endpoint(endpoint)
+        .build()
+        .map_err(|e| ProxyError::Config(format!("Failed to set up OTLP export: {}", e)))?;
+
+    let provider = SdkTracerProvider::builder()
+        .with_batch_exporter(exporter)
+        .with_resource(Resource::builder().with_service_name(service_name).build())
+        .build();
+    global::set_text_map_propagator(TraceContextPropagator::new());
+
+    let tracer = provider.tracer(service_name);
+    Ok((
+        tracer,
+        TelemetryGuard {
+            provider: Some(provider),
+        },
+    ))
+}
+
+/// Trace context of `span` to carry through the tunnel (empty without OTLP export)
+pub fn inject_context(span: &Span) -> HashMap<String, String> {
+    let mut carrier = HashMap::new();
+    global::get_text_map_propagator(|propagator| {
+        propagator.inject_context(&span.context(), &mut carrier)
+    });
+    carrier
+}
+
+/// Makes `span` a child of the trace context received from the other side
+pub fn set_parent(span: &Span, carrier: &HashMap<String, String>) {
+    if carrier.is_empty() {
+        return;
+    }
+    let parent = global::get_text_map_propagator(|propagator| propagator.extract(carrier));
+    let _ = span.set_parent(parent);
+}
+
+/// Makes `span` a child of the trace context in the request headers, if any
+pub fn set_parent_from_headers(span: &Span, headers: &HeaderMap) {
+    let parent =
+        global::get_text_map_propagator(|propagator| propagator.extract(&HeaderExtractor(headers)));
+    if !is_empty(&parent) {
+        let _ = span.set_parent(parent);
+    }
+}
+
+/// Replaces the trace context headers with the context of `span`
+pub fn inject_headers(span: &Span, headers: &mut Vec<(String, String)>) {
+    let context = span.context();
+    if is_empty(&context) {
+        return;
+    }
+    headers.retain(|(name, _)| {
+        !TRACE_CONTEXT_HEADERS
+            .iter()
+            .any(|header| name.eq_ignore_ascii_case(header))
+    });
+    global::get_text_map_propagator(|propagator| {
+        propagator.inject_context(&context, &mut HeaderInjector(headers))
+    });
+}
+
+fn is_empty(context: &Context) -> bool {
+    !context.span().span_context().is_valid()
+}
+
+struct HeaderInjector<'a>(&'a mut Vec<(String, String)>);
+
+impl Injector for HeaderInjector<'_> {
+    fn set(&mut self, key: &str, value: String) {
+        self.0.push((key.to_string(), value));
+    }
+}
+
+struct HeaderExtractor<'a>(&'a HeaderMap);
+
+impl Extractor for HeaderExtractor<'_> {
+    fn get(&self, key: &str) -> Option<&str> {
+        self.0.get(key).and_then(|value| value.to_str().ok())
+    }
+
+    fn keys(&self) -> Vec<&str> {
+        self.0.keys().map(|name| name.as_str()).collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use opentelemetry::trace::TraceId;
+    use tracing::info_span;
+    use tracing_subscriber::layer::SubscriberExt;
+
+    const INCOMING_TRACE_ID: &str = "0af7651916cd43dd8448eb211c80319c";
+
+    /// Runs `f` with spans recorded by an OpenTelemetry tracer
+    fn with_tracer(f: impl FnOnce()) {
+        global::set_text_map_propagator(TraceContextPropagator::new());
+        let provider = SdkTracerProvider::builder().build();
+        let subscriber = tracing_subscriber::registry()
+            .with(tracing_opentelemetry::layer().with_tracer(provider.tracer("test")));
+        tracing::subscriber::with_default(subscriber, f);
+    }
+
+    fn trace_id(span: &Span) -> TraceId {
+        span.context().span().span_context().trace_id()
+    }
+
+    fn headers(values: &[(&str, &str)]) -> Vec<(String, String)> {
+        values
+            .iter()
+            .map(|(n, v)| (n.to_string(), v.to_string()))
+            .collect()
+    }
+
+    #[test]
+    fn test_context_round_trip() {
+        with_tracer(|| {
+            let span = info_span!("server");
+            let carrier = inject_context(&span);
+            assert!(carrier.contains_key("traceparent"));
+
+            let child = info_span!("client");
+            set_parent(&child, &carrier);
+            assert_eq!(trace_id(&child), trace_id(&span));
+        });
+    }
+
+    #[test]
+    fn test_inject_headers_replaces_trace_context() {
+        with_tracer(|| {
+            let span = info_span!("request");
+            let mut headers = headers(&[
+                (
+                    "Traceparent",
+                    "00-0af7651916cd43dd8448eb211c80319c-b7ad6b7169203331-01",
+                ),
+                ("tracestate", "vendor=1"),
+                ("accept", "*/*"),
+            ]);
+            inject_headers(&span, &mut headers);
+
+            let traceparent: Vec<&str> = headers
+                .iter()
+                .filter(|(name, _)| name.eq_ignore_ascii_case("traceparent"))
+                .map(|(_, value)| value.as_str())
+                .collect();
+            assert_eq!(traceparent.len(), 1);
+            assert!(traceparent[0].contains(&trace_id(&span).to_string()));
+            assert!(!traceparent[0].contains(INCOMING_TRACE_ID));
+            assert!(!headers.iter().any(|(_, value)| value == "vendor=1"));
+            assert!(headers.contains(&("accept".to_string(), "*/*".to_string())));
+        });
+    }
+
+    #[test]
+    fn test_inject_headers_without_context() {
+        // Without a tracer the span has no valid context
+        let span = info_span!("request");
+        let original = headers(&[
+            (
+                "traceparent",
+                "00-0af7651916cd43dd8448eb211c80319c-b7ad6b7169203331-01",
+            ),
+            ("accept", "*/*"),
+        ]);
+        let mut headers = original.clone();
+        inject_headers(&span, &mut headers);
+        assert_eq!(headers, original);
+    }
+}
@@ -1,7 +1,15 @@
+use crate::compression::Compression;
 use crate::error::ProxyError;
 use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
 use tokio_tungstenite::tungstenite::Message;
 
+/// Header carrying the ID a request is correlated by across all hops
+pub const REQUEST_ID_HEADER: &str = "x-request-id";
+
+/// Largest body accepted from callers, and when decompressing tunnel bodies
+pub const MAX_BODY_SIZE: usize = 10 * 1024 * 1024;
+
 #[derive(Debug, Clone, Serialize, Deserialize)]
 #[serde(tag = "type", rename_all = "snake_case")]
 pub enum TunnelMessage {
@@ -10,12 +18,27 @@ pub enum TunnelMessage {
         client_id: String,
         timestamp: u64,
         signature: String,
+        /// Body compressions supported by the client, in order of preference
+        #[serde(default)]
+        compression: Vec<Compression>,
+        /// Lower values are preferred by the `priority` load balancing strategy
+        #[serde(default)]
+        priority: u32,
+        /// Share of requests among clients with the same priority
+        #[serde(default = "default_weight")]
+        weight: u32,
+        /// Version of the client software
+        #[serde(default)]
+        version: Option<String>,
     },
 
     /// Authentication response
     AuthResponse {
         success: bool,
         message: Option<String>,
+        /// Body compression chosen by the server for this connection
+        #[serde(default)]
+        compression: Option<Compression>,
     },
 
     /// HTTP request to forward
@@ -27,7 +50,17 @@ pub enum TunnelMessage {
         headers: Vec<(String, String)>,
         #[serde(with = "base64")]
         body: Option<Vec<u8>>,
+        /// Compression applied to `body`
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        body_compression: Option<Compression>,
         source_ip: Option<String>,
+        /// ID of the request, stable across retries and passed to Home
+        /// Assistant as `X-Request-Id` (`request_id` changes per attempt)
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        correlation_id: Option<String>,
+        /// W3C trace context of the server span handling the request
+        #[serde(default, skip_serializing_if = "HashMap::is_empty")]
+        trace_context: HashMap<String, String>,
     },
 
     /// HTTP response from upstream
@@ -37,6 +70,12 @@ pub enum TunnelMessage {
         headers: Vec<(String, String)>,
         #[serde(with = "base64")]
         body: Option<Vec<u8>>,
+        /// Compression applied to `body`
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        body_compression: Option<Compression>,
+        /// Time Home Assistant took to answer, in milliseconds
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        upstream_ms: Option<u64>,
     },
 
     /// Error response
@@ -46,7 +85,16 @@ pub enum TunnelMessage {
         message: String,
     },
 
-    /// Heartbeat
+    /// Sent by either side before it shuts down. The receiver stops sending
+    /// new requests over this connection, requests already in flight are
+    /// still completed within the grace period (in seconds). A client
+    /// receiving this from the server should open a replacement connection.
+    GoingAway {
+        grace_period: u64,
+    },
+
+    /// Heartbeat, sent by either side and answered with a `Pong` echoing
+    /// the timestamp
     Ping {
         timestamp: u64,
     },
@@ -78,6 +126,10 @@ impl TunnelMessage {
     }
 }
 
+fn default_weight() -> u32 {
+    1
+}
+
 pub fn generate_auth_signature(client_id: &str, timestamp: u64, secret: &str) -> String {
     use hmac::{Hmac, Mac};
     use sha2::Sha256;
@@ -0,0 +1,179 @@
+"use strict";
+
+const REFRESH_INTERVAL_MS = 2000;
+const TOKEN_KEY = "ha-tunnel-admin-token";
+
+async function fetchJson(path) {
+  const headers = {};
+  const token = localStorage.getItem(TOKEN_KEY);
+  if (token) {
+    headers["Authorization"] = "Bearer " + token;
+  }
+
+  const response = await fetch(path, { headers });
+  if (response.status === 401) {
+    throw new Error("unauthorized");
+  }
+  if (!response.ok) {
+    throw new Error("HTTP " + response.status);
+  }
+  return response.json();
+}
+
+function cell(text, className) {
+  const td = document.createElement("td");
+  td.textContent = text;
+  if (className) {
+    td.className = className;
+  }
+  return td;
+}
+
+function row(cells) {
+  const tr = document.createElement("tr");
+  cells.forEach((td) => tr.appendChild(td));
+  return tr;
+}
+
+function percent(rate) {
+  return (rate * 100).toFixed(1) + "%";
+}
+
+function duration(seconds) {
+  const units = [["d", 86400], ["h", 3600], ["m", 60]];
+  for (const [unit, size] of units) {
+    if (seconds >= size) {
+      return Math.floor(seconds / size) + unit;
+    }
+  }
+  return seconds + "s";
+}
+
+function time(timestamp) {
+  return new Date(timestamp * 1000).toLocaleTimeString();
+}
+
+function short(id) {
+  return id.length > 12 ? id.slice(0, 8) + "…" : id;
+}
+
+function renderStatus(stats) {
+  const status = document.getElementById("status");
+  if (stats.connections === 0) {
+    status.textContent = "no clients";
+    status.className = "badge error";
+  } else if (stats.error_rate_5m > 0.05) {
+    status.textContent = "degraded";
+    status.className = "badge warn";
+  } else {
+    status.textContent = "ok";
+    status.className = "badge ok";
+  }
+
+  document.getElementById("clients").textContent = stats.clients;
+  document.getElementById("connections").textContent = stats.connections;
+  document.getElementById("in-flight").textContent = stats.in_flight;
+  document.getElementById("total-requests").textContent = stats.total_requests;
+  document.getElementById("error-rate-5m").textContent = percent(stats.error_rate_5m);
+  document.getElementById("error-rate-1h").textContent = percent(stats.error_rate_1h);
+  document.getElementById("uptime").textContent = duration(stats.uptime_s);
+  document.getElementById("updated").textContent = "updated " + new Date().toLocaleTimeString();
+}
+
+function renderHistory(history) {
+  const container = document.getElementById("history");
+  const max = Math.max(1, ...history.map((minute) => minute.requests));
+  container.replaceChildren(
+    ...history.map((minute) => {
+      const bar = document.createElement("div");
+      bar.className = "bar";
+      bar.title = time(minute.minute) + ": " + minute.requests + " requests, " + minute.errors + " errors";
+
+      const ok = document.createElement("div");
+      ok.className = "ok";
+      ok.style.height = ((minute.requests - minute.errors) / max) * 100 + "%";
+      const errors = document.createElement("div");
+      errors.className = "error";
+      errors.style.height = (minute.errors / max) * 100 + "%";
+
+      bar.append(ok, errors);
+      return bar;
+    })
+  );
+}
+
+function renderClients(connections) {
+  document.getElementById("client-rows").replaceChildren(
+    ...connections.map((connection) =>
+      row([
+        cell(connection.client_id),
+        cell(short(connection.connection_id)),
+        cell(connection.remote_addr),
+        cell(connection.version || "-"),
+        cell(time(connection.connected_at)),
+        cell(connection.in_flight),
+        cell(connection.rtt_ms === null ? "-" : connection.rtt_ms + " ms"),
+        cell(connection.priority + " / " + connection.weight),
+      ])
+    )
+  );
+}
+
+function renderRequests(requests) {
+  document.getElementById("request-rows").replaceChildren(
+    ...requests.map((request) =>
+      row([
+        cell(time(request.timestamp)),
+        cell(request.method),
+        cell(request.path, "path"),
+        cell(request.status, "status-" + Math.floor(request.status / 100) + "xx"),
+        cell(request.latency_ms + " ms"),
+        cell(request.client_id || "-"),
+      ])
+    )
+  );
+}
+
+// Returns false if the prompt was cancelled
+function askToken() {
+  const entered = prompt("Admin token");
+  if (!entered) {
+    return false;
+  }
+  localStorage.setItem(TOKEN_KEY, entered);
+  return true;
+}
+
+async function refresh() {
+  const status = document.getElementById("status");
+  try {
+    const [stats, connections] = await Promise.all([
+      fetchJson("/admin/stats"),
+      fetchJson("/admin/clients"),
+    ]);
+    renderStatus(stats);
+    renderHistory(stats.history);
+    renderClients(connections);
+    renderRequests(stats.recent_requests);
+  } catch (e) {
+    if (e.message === "unauthorized" && !askToken()) {
+      // Stop polling until a token is entered through the retry button
+      status.textContent = "token required";
+      status.className = "badge warn";
+      document.getElementById("token-retry").hidden = false;
+      return;
+    }
+    status.textContent = "unavailable";
+    status.className = "badge error";
+  }
+  setTimeout(refresh, REFRESH_INTERVAL_MS);
+}
+
+document.getElementById("token-retry").addEventListener("click", (event) => {
+  if (askToken()) {
+    event.target.hidden = true;
+    refresh();
+  }
+});
+
+refresh();
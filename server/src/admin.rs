@@ -0,0 +1,284 @@
+use crate::ServerState;
+use crate::dashboard::create_dashboard_router;
+use crate::stats::{RequestRecord, StatsSummary};
+use axum::Router;
+use axum::extract::{Path, Request, State};
+use axum::http::StatusCode;
+use axum::middleware::{self, Next};
+use axum::response::{IntoResponse, Response};
+use axum::routing::{delete, get, post};
+use common::now_as_secs;
+use serde::Serialize;
+use std::collections::HashSet;
+use std::sync::Arc;
+use tracing::{info, warn};
+
+#[derive(Debug, Serialize)]
+struct ConnectionInfo {
+    connection_id: String,
+    client_id: String,
+    connected_at: u64,
+    last_ping: u64,
+    remote_addr: String,
+    version: Option<String>,
+    in_flight: usize,
+    rtt_ms: Option<u128>,
+    priority: u32,
+    weight: u32,
+}
+
+#[derive(Debug, Serialize)]
+struct PendingRequestInfo {
+    request_id: String,
+    client_id: String,
+    connection_id: String,
+    method: String,
+    path: String,
+    age_ms: u128,
+}
+
+#[derive(Debug, Serialize)]
+struct Stats {
+    clients: usize,
+    connections: usize,
+    in_flight: usize,
+    #[serde(flatten)]
+    summary: StatsSummary,
+    recent_requests: Vec<RequestRecord>,
+}
+
+/// Routes of the admin API. Without a separate bind address the API shares
+/// the public listener and `token_required` rejects every request while no
+/// `admin_token` is configured.
+pub fn create_admin_router(state: Arc<ServerState>, token_required: bool) -> Router {
+    Router::new()
+        .route("/clients", get(list_clients))
+        .route("/clients/{client_id}", delete(disconnect_client))
+        .route("/clients/{client_id}/revoke", post(revoke_client))
+        .route(
+            "/connections/{connection_id}",
+            delete(disconnect_connection),
+        )
+        .route("/requests", get(list_requests))
+        .route("/stats", get(get_stats))
+        .layer(middleware::from_fn_with_state(
+            (state.clone(), token_required),
+            require_token,
+        ))
+        .with_state(state)
+        // The dashboard assets hold no data, the page asks for the token itself
+        .merge(create_dashboard_router())
+}
+
+async fn require_token(
+    State((state, token_required)): State<(Arc<ServerState>, bool)>,
+    request: Request,
+    next: Next,
+) -> Response {
+    let authorization = request
+        .headers()
+        .get("authorization")
+        .and_then(|value| value.to_str().ok());
+    match check_token(
+        state.config().admin_token.as_deref(),
+        token_required,
+        authorization,
+    ) {
+        Ok(()) => next.run(request).await,
+        Err(StatusCode::UNAUTHORIZED) => {
+            warn!(path = %request.uri().path(), "Admin API request with invalid token");
+            StatusCode::UNAUTHORIZED.into_response()
+        }
+        Err(status) => status.into_response(),
+    }
+}
+
+/// Checks the `Authorization` header of an admin request against the
+/// configured token. Without a token the API is hidden (404) if
+/// `token_required`, and open otherwise.
+fn check_token(
+    token: Option<&str>,
+    token_required: bool,
+    authorization: Option<&str>,
+) -> Result<(), StatusCode> {
+    let Some(token) = token else {
+        return if token_required {
+            Err(StatusCode::NOT_FOUND)
+        } else {
+            Ok(())
+        };
+    };
+
+    let provided = authorization
+        .and_then(|value| value.strip_prefix("Bearer "))
+        .unwrap_or_default();
+    if !constant_time_eq(provided.as_bytes(), token.as_bytes()) {
+        return Err(StatusCode::UNAUTHORIZED);
+    }
+    Ok(())
+}
+
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0, |acc, (x, y)| acc | (x ^ y)) == 0
+}
+
+async fn list_clients(State(state): State<Arc<ServerState>>) -> impl IntoResponse {
+    let mut connections: Vec<ConnectionInfo> = state
+        .clients
+        .iter()
+        .map(|client| ConnectionInfo {
+            connection_id: client.connection_id.clone(),
+            client_id: client.client_id.clone(),
+            connected_at: client.connected_at,
+            last_ping: client.last_ping,
+            remote_addr: client.remote_addr.to_string(),
+            version: client.version.clone(),
+            in_flight: state
+                .pending_requests
+                .iter()
+                .filter(|pending| pending.connection_id == client.connection_id)
+                .count(),
+            rtt_ms: client.rtt.map(|rtt| rtt.as_millis()),
+            priority: client.priority,
+            weight: client.weight,
+        })
+        .collect();
+    connections.sort_by_key(|c| c.connected_at);
+
+    axum::Json(connections)
+}
+
+async fn list_requests(State(state): State<Arc<ServerState>>) -> impl IntoResponse {
+    let mut requests: Vec<PendingRequestInfo> = state
+        .pending_requests
+        .iter()
+        .map(|pending| PendingRequestInfo {
+            request_id: pending.key().clone(),
+            client_id: pending.client_id.clone(),
+            connection_id: pending.connection_id.clone(),
+            method: pending.method.clone(),
+            path: pending.path.clone(),
+            age_ms: pending.started_at.elapsed().as_millis(),
+        })
+        .collect();
+    requests.sort_by_key(|r| std::cmp::Reverse(r.age_ms));
+
+    axum::Json(requests)
+}
+
+async fn get_stats(State(state): State<Arc<ServerState>>) -> impl IntoResponse {
+    let clients = state
+        .clients
+        .iter()
+        .map(|client| client.client_id.clone())
+        .collect::<HashSet<_>>()
+        .len();
+
+    axum::Json(Stats {
+        clients,
+        connections: state.clients.len(),
+        in_flight: state.pending_requests.len(),
+        summary: state.request_stats.summary(now_as_secs()),
+        recent_requests: state.request_stats.recent(),
+    })
+}
+
+/// Closes all connections of a client and fails its requests in flight right
+/// away. The client is free to reconnect unless it was revoked.
+pub fn close_client_connections(state: &ServerState, client_id: &str) -> usize {
+    let connections: Vec<_> = state
+        .clients
+        .iter()
+        .filter(|client| client.client_id == client_id)
+        .map(|client| client.close.clone())
+        .collect();
+    for close in &connections {
+        close.notify_one();
+    }
+    // Dropping the response senders answers the waiting callers
+    state
+        .pending_requests
+        .retain(|_, pending| pending.client_id != client_id);
+    connections.len()
+}
+
+async fn disconnect_client(
+    State(state): State<Arc<ServerState>>,
+    Path(client_id): Path<String>,
+) -> StatusCode {
+    match close_client_connections(&state, &client_id) {
+        0 => StatusCode::NOT_FOUND,
+        count => {
+            info!(client_id = %client_id, connections = count, "Client disconnected by admin");
+            StatusCode::NO_CONTENT
+        }
+    }
+}
+
+async fn disconnect_connection(
+    State(state): State<Arc<ServerState>>,
+    Path(connection_id): Path<String>,
+) -> StatusCode {
+    match state.clients.get(&connection_id) {
+        Some(client) => {
+            client.close.notify_one();
+            info!(connection_id = %connection_id, "Connection closed by admin");
+            StatusCode::NO_CONTENT
+        }
+        None => StatusCode::NOT_FOUND,
+    }
+}
+
+/// Rejects future authentications of the client_id (until restart, add it to
+/// `revoked_clients` to make it permanent) and disconnects it
+async fn revoke_client(
+    State(state): State<Arc<ServerState>>,
+    Path(client_id): Path<String>,
+) -> StatusCode {
+    state.revoked_clients.insert(client_id.clone());
+    let count = close_client_connections(&state, &client_id);
+    info!(client_id = %client_id, connections = count, "Client revoked by admin");
+
+    StatusCode::NO_CONTENT
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_check_token() {
+        assert_eq!(
+            check_token(Some("adm"), true, None),
+            Err(StatusCode::UNAUTHORIZED)
+        );
+        assert_eq!(
+            check_token(Some("adm"), true, Some("Bearer wrong")),
+            Err(StatusCode::UNAUTHORIZED)
+        );
+        assert_eq!(
+            check_token(Some("adm"), false, Some("adm")),
+            Err(StatusCode::UNAUTHORIZED)
+        );
+        assert_eq!(check_token(Some("adm"), true, Some("Bearer adm")), Ok(()));
+        assert_eq!(check_token(Some("adm"), false, Some("Bearer adm")), Ok(()));
+    }
+
+    #[test]
+    fn test_check_token_without_token() {
+        assert_eq!(
+            check_token(None, true, Some("Bearer adm")),
+            Err(StatusCode::NOT_FOUND)
+        );
+        assert_eq!(check_token(None, false, None), Ok(()));
+    }
+
+    #[test]
+    fn test_constant_time_eq() {
+        assert!(constant_time_eq(b"secret", b"secret"));
+        assert!(!constant_time_eq(b"secret", b"secreT"));
+        assert!(!constant_time_eq(b"secret", b"secret2"));
+        assert!(!constant_time_eq(b"", b"secret"));
+        assert!(constant_time_eq(b"", b""));
+    }
+}
@@ -0,0 +1,196 @@
+use common::error::ProxyError;
+use std::str::FromStr;
+use std::time::Duration;
+
+/// How the server picks the connection a request is sent to
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum LoadBalancing {
+    /// Rotate through all connections
+    #[default]
+    RoundRobin,
+    /// Prefer the client with the fewest requests in flight
+    LeastOutstanding,
+    /// Prefer the connection with the lowest heartbeat round trip time
+    LowestLatency,
+    /// Only use clients with the best (lowest) priority, spread by weight
+    Priority,
+}
+
+impl FromStr for LoadBalancing {
+    type Err = ProxyError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().replace('-', "_").as_str() {
+            "round_robin" => Ok(LoadBalancing::RoundRobin),
+            "least_outstanding" => Ok(LoadBalancing::LeastOutstanding),
+            "lowest_latency" | "lowest_rtt" => Ok(LoadBalancing::LowestLatency),
+            "priority" | "weighted" => Ok(LoadBalancing::Priority),
+            other => Err(ProxyError::Config(format!(
+                "Unknown load balancing strategy: {}",
+                other
+            ))),
+        }
+    }
+}
+
+/// What the strategies know about a connection
+#[derive(Debug, Clone, Default)]
+pub struct Candidate {
+    /// Requests in flight to the client owning the connection
+    pub outstanding: usize,
+    /// Last measured heartbeat round trip time (None = not measured yet)
+    pub rtt: Option<Duration>,
+    pub priority: u32,
+    pub weight: u32,
+}
+
+impl LoadBalancing {
+    /// Returns the index of the candidate to use. `counter` increases with
+    /// every request and rotates between otherwise equal candidates.
+    pub fn select(&self, candidates: &[Candidate], counter: usize) -> Option<usize> {
+        if candidates.is_empty() {
+            return None;
+        }
+        let len = candidates.len();
+        let rotated = (0..len).map(|i| (counter + i) % len);
+
+        match self {
+            LoadBalancing::RoundRobin => Some(counter % len),
+            LoadBalancing::LeastOutstanding => rotated.min_by_key(|&i| candidates[i].outstanding),
+            LoadBalancing::LowestLatency => {
+                rotated.min_by_key(|&i| candidates[i].rtt.unwrap_or(Duration::MAX))
+            }
+            LoadBalancing::Priority => {
+                let best = candidates.iter().map(|c| c.priority).min()?;
+                let preferred: Vec<usize> = (0..len)
+                    .filter(|&i| candidates[i].priority == best)
+                    .collect();
+
+                // Weighted round robin, connections with a weight of 0 are only
+                // used if no other connection with the same priority has a weight
+                let standby_only = preferred.iter().all(|&i| candidates[i].weight == 0);
+                let weight_of = |i: usize| {
+                    if standby_only {
+                        1
+                    } else {
+                        candidates[i].weight as usize
+                    }
+                };
+                let total: usize = preferred.iter().map(|&i| weight_of(i)).sum();
+                let mut slot = counter % total;
+                for i in preferred {
+                    let weight = weight_of(i);
+                    if slot < weight {
+                        return Some(i);
+                    }
+                    slot -= weight;
+                }
+                None
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn picks(strategy: LoadBalancing, candidates: &[Candidate], requests: usize) -> Vec<usize> {
+        (0..requests)
+            .map(|counter| strategy.select(candidates, counter).unwrap())
+            .collect()
+    }
+
+    #[test]
+    fn test_least_outstanding_rotates_ties() {
+        let candidates = vec![
+            Candidate {
+                outstanding: 2,
+                ..Default::default()
+            },
+            Candidate::default(),
+            Candidate::default(),
+        ];
+        assert_eq!(
+            picks(LoadBalancing::LeastOutstanding, &candidates, 4),
+            vec![1, 1, 2, 1]
+        );
+    }
+
+    #[test]
+    fn test_lowest_latency_prefers_measured() {
+        let candidates = vec![
+            Candidate::default(),
+            Candidate {
+                rtt: Some(Duration::from_millis(80)),
+                ..Default::default()
+            },
+            Candidate {
+                rtt: Some(Duration::from_millis(20)),
+                ..Default::default()
+            },
+        ];
+        assert_eq!(
+            picks(LoadBalancing::LowestLatency, &candidates, 3),
+            vec![2, 2, 2]
+        );
+    }
+
+    #[test]
+    fn test_priority_weights() {
+        let candidates = vec![
+            Candidate {
+                priority: 1,
+                weight: 5,
+                ..Default::default()
+            },
+            Candidate {
+                priority: 0,
+                weight: 3,
+                ..Default::default()
+            },
+            Candidate {
+                priority: 0,
+                weight: 1,
+                ..Default::default()
+            },
+        ];
+        assert_eq!(
+            picks(LoadBalancing::Priority, &candidates, 8),
+            vec![1, 1, 1, 2, 1, 1, 1, 2]
+        );
+    }
+
+    #[test]
+    fn test_zero_weight_is_standby() {
+        let standby = Candidate {
+            weight: 0,
+            ..Default::default()
+        };
+        let active = Candidate {
+            weight: 2,
+            ..Default::default()
+        };
+        assert_eq!(
+            picks(
+                LoadBalancing::Priority,
+                &[standby.clone(), active.clone()],
+                4
+            ),
+            vec![1, 1, 1, 1]
+        );
+        assert_eq!(
+            picks(LoadBalancing::Priority, &[standby.clone(), standby], 4),
+            vec![0, 1, 0, 1]
+        );
+    }
+
+    #[test]
+    fn test_parse() {
+        assert_eq!(
+            "least-outstanding".parse::<LoadBalancing>().unwrap(),
+            LoadBalancing::LeastOutstanding
+        );
+        assert!("random".parse::<LoadBalancing>().is_err());
+    }
+}
@@ -0,0 +1,473 @@
+use dashmap::DashMap;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::{Duration, Instant};
+use tracing::debug;
+
+/// Request headers that make the caller responsible for revalidation, such
+/// requests bypass the cache
+const CONDITIONAL_HEADERS: [&str; 4] = [
+    "if-none-match",
+    "if-modified-since",
+    "if-match",
+    "if-unmodified-since",
+];
+
+/// Response headers a 304 must not replace in the stored response
+const KEPT_HEADERS: [&str; 3] = ["content-length", "content-encoding", "transfer-encoding"];
+
+#[derive(Debug, Clone)]
+pub struct CachedResponse {
+    pub status: u16,
+    pub headers: Vec<(String, String)>,
+    pub body: Vec<u8>,
+    /// Request headers named by `Vary` and the values the response was
+    /// negotiated with
+    vary: Vec<(String, Option<String>)>,
+    stored_at: Instant,
+    max_age: Duration,
+}
+
+impl CachedResponse {
+    pub fn is_fresh(&self) -> bool {
+        self.stored_at.elapsed() < self.max_age
+    }
+
+    /// Returns true if the response was negotiated for the same values of
+    /// the headers named by `Vary`
+    fn matches(&self, request_headers: &[(String, String)]) -> bool {
+        self.vary
+            .iter()
+            .all(|(name, value)| find_header(request_headers, name) == value.as_deref())
+    }
+
+    /// Applies the lifetime and headers of a 304 answer to a revalidation
+    fn refresh(&mut self, headers: &[(String, String)]) {
+        let cache_control = CacheControl::parse(find_header(headers, "cache-control"));
+        if let Some(max_age) = cache_control.max_age
+            && !cache_control.no_cache
+        {
+            self.max_age = max_age;
+        }
+        self.stored_at = Instant::now();
+
+        let updated: Vec<_> = headers
+            .iter()
+            .filter(|(name, _)| {
+                !KEPT_HEADERS
+                    .iter()
+                    .any(|kept| name.eq_ignore_ascii_case(kept))
+            })
+            .cloned()
+            .collect();
+        self.headers
+            .retain(|(name, _)| !updated.iter().any(|(n, _)| n.eq_ignore_ascii_case(name)));
+        self.headers.extend(updated);
+    }
+
+    /// Conditional request headers to revalidate this entry with upstream
+    pub fn validators(&self) -> Vec<(String, String)> {
+        let mut validators = vec![];
+        if let Some(etag) = find_header(&self.headers, "etag") {
+            validators.push(("if-none-match".to_string(), etag.to_string()));
+        }
+        if let Some(last_modified) = find_header(&self.headers, "last-modified") {
+            validators.push(("if-modified-since".to_string(), last_modified.to_string()));
+        }
+        validators
+    }
+
+    fn size(&self) -> usize {
+        self.body.len()
+            + self
+                .headers
+                .iter()
+                .map(|(name, value)| name.len() + value.len())
+                .sum::<usize>()
+    }
+}
+
+/// In-memory cache for responses to idempotent GET requests
+pub struct ResponseCache {
+    /// Path prefixes responses are cached for (empty = cache disabled)
+    routes: Vec<String>,
+    max_entry_size: usize,
+    max_size: usize,
+    entries: DashMap<String, CachedResponse>,
+    size: AtomicUsize,
+}
+
+impl ResponseCache {
+    pub fn new(routes: Vec<String>, max_entry_size: usize, max_size: usize) -> Self {
+        Self {
+            routes,
+            max_entry_size,
+            max_size,
+            entries: DashMap::new(),
+            size: AtomicUsize::new(0),
+        }
+    }
+
+    /// Returns true if a request may be answered from (and stored in) the
+    /// cache. Authorized and conditional requests always go upstream.
+    pub fn is_cacheable_request(
+        &self,
+        method: &str,
+        path: &str,
+        headers: &[(String, String)],
+    ) -> bool {
+        method == "GET"
+            && self
+                .routes
+                .iter()
+                .any(|route| path.starts_with(route.as_str()))
+            && !headers.iter().any(|(name, _)| {
+                let name = name.to_lowercase();
+                name == "authorization" || CONDITIONAL_HEADERS.contains(&name.as_str())
+            })
+    }
+
+    pub fn key(path: &str, query: Option<&str>) -> String {
+        match query {
+            Some(query) => format!("{}?{}", path, query),
+            None => path.to_string(),
+        }
+    }
+
+    /// Returns the entry for `key` if it was negotiated for the same request
+    /// headers
+    pub fn get(&self, key: &str, request_headers: &[(String, String)]) -> Option<CachedResponse> {
+        self.entries
+            .get(key)
+            .filter(|entry| entry.matches(request_headers))
+            .map(|entry| entry.clone())
+    }
+
+    /// Stores a response if its headers allow it. Responses without an
+    /// explicit lifetime are only stored if they carry validators and are then
+    /// revalidated on every use. Encoded responses are only stored if they
+    /// vary by `Accept-Encoding`, so they are served to matching callers only.
+    pub fn store(
+        &self,
+        key: String,
+        request_headers: &[(String, String)],
+        status: u16,
+        headers: &[(String, String)],
+        body: &[u8],
+    ) {
+        if status != 200 || find_header(headers, "set-cookie").is_some() {
+            return;
+        }
+
+        let Some(vary) = vary_headers(headers) else {
+            return;
+        };
+        let encoded = find_header(headers, "content-encoding")
+            .is_some_and(|encoding| !encoding.eq_ignore_ascii_case("identity"));
+        if encoded && !vary.iter().any(|name| name == "accept-encoding") {
+            return;
+        }
+
+        let cache_control = CacheControl::parse(find_header(headers, "cache-control"));
+        if cache_control.no_store || cache_control.private {
+            return;
+        }
+
+        let has_validators = find_header(headers, "etag").is_some()
+            || find_header(headers, "last-modified").is_some();
+        let max_age = match cache_control.max_age {
+            Some(_) if cache_control.no_cache => Duration::ZERO,
+            Some(max_age) => max_age,
+            None if has_validators => Duration::ZERO,
+            None => return,
+        };
+        if max_age.is_zero() && !has_validators {
+            return;
+        }
+
+        let vary = vary
+            .into_iter()
+            .map(|name| {
+                let value = find_header(request_headers, &name).map(str::to_string);
+                (name, value)
+            })
+            .collect();
+        self.insert(
+            key,
+            CachedResponse {
+                status,
+                headers: headers.to_vec(),
+                body: body.to_vec(),
+                vary,
+                stored_at: Instant::now(),
+                max_age,
+            },
+        );
+    }
+
+    /// Refreshes the entry a revalidation was sent for after upstream
+    /// answered with 304. The entry is stored again if it was evicted in the
+    /// meantime.
+    pub fn revalidated(
+        &self,
+        key: String,
+        mut entry: CachedResponse,
+        headers: &[(String, String)],
+    ) -> CachedResponse {
+        entry.refresh(headers);
+        self.insert(key, entry.clone());
+        entry
+    }
+
+    fn insert(&self, key: String, entry: CachedResponse) {
+        let size = entry.size();
+        if size > self.max_entry_size || size > self.max_size {
+            debug!(key = %key, size = size, "Response too large to cache");
+            return;
+        }
+
+        self.remove(&key);
+        self.evict(size);
+        self.size.fetch_add(size, Ordering::Relaxed);
+        self.entries.insert(key, entry);
+    }
+
+    fn remove(&self, key: &str) {
+        if let Some((_, entry)) = self.entries.remove(key) {
+            self.size.fetch_sub(entry.size(), Ordering::Relaxed);
+        }
+    }
+
+    /// Drops the oldest entries until `additional` bytes fit into the cache
+    fn evict(&self, additional: usize) {
+        while self.size.load(Ordering::Relaxed) + additional > self.max_size {
+            let oldest = self
+                .entries
+                .iter()
+                .min_by_key(|entry| entry.stored_at)
+                .map(|entry| entry.key().clone());
+            match oldest {
+                Some(key) => self.remove(&key),
+                None => break,
+            }
+        }
+    }
+}
+
+#[derive(Debug, Default)]
+struct CacheControl {
+    no_store: bool,
+    no_cache: bool,
+    private: bool,
+    max_age: Option<Duration>,
+}
+
+impl CacheControl {
+    fn parse(value: Option<&str>) -> Self {
+        let mut cache_control = CacheControl::default();
+        for directive in value.unwrap_or_default().split(',') {
+            let directive = directive.trim().to_lowercase();
+            let (name, value) = match directive.split_once('=') {
+                Some((name, value)) => (name.trim(), Some(value.trim().trim_matches('"'))),
+                None => (directive.as_str(), None),
+            };
+            match (name, value) {
+                ("no-store", _) => cache_control.no_store = true,
+                ("no-cache", _) => cache_control.no_cache = true,
+                ("private", _) => cache_control.private = true,
+                // s-maxage is meant for shared caches like this one and wins over max-age
+                ("s-maxage", Some(v)) => {
+                    cache_control.max_age = v.parse().ok().map(Duration::from_secs)
+                }
+                ("max-age", Some(v)) if cache_control.max_age.is_none() => {
+                    cache_control.max_age = v.parse().ok().map(Duration::from_secs)
+                }
+                _ => {}
+            }
+        }
+        cache_control
+    }
+}
+
+/// Lowercase request header names of the `Vary` response headers, None for
+/// `Vary: *` which no cached response can satisfy
+fn vary_headers(headers: &[(String, String)]) -> Option<Vec<String>> {
+    let mut names = vec![];
+    for (_, value) in headers
+        .iter()
+        .filter(|(name, _)| name.eq_ignore_ascii_case("vary"))
+    {
+        for name in value.split(',').map(str::trim).filter(|n| !n.is_empty()) {
+            if name == "*" {
+                return None;
+            }
+            names.push(name.to_lowercase());
+        }
+    }
+    Some(names)
+}
+
+fn find_header<'a>(headers: &'a [(String, String)], name: &str) -> Option<&'a str> {
+    headers
+        .iter()
+        .find(|(n, _)| n.eq_ignore_ascii_case(name))
+        .map(|(_, v)| v.as_str())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn headers(values: &[(&str, &str)]) -> Vec<(String, String)> {
+        values
+            .iter()
+            .map(|(n, v)| (n.to_string(), v.to_string()))
+            .collect()
+    }
+
+    #[test]
+    fn test_store_honors_cache_control() {
+        let cache = ResponseCache::new(vec!["/static/".to_string()], 1024, 4096);
+
+        cache.store(
+            "/a".to_string(),
+            &[],
+            200,
+            &headers(&[("cache-control", "max-age=60")]),
+            b"a",
+        );
+        cache.store(
+            "/b".to_string(),
+            &[],
+            200,
+            &headers(&[("cache-control", "no-store")]),
+            b"b",
+        );
+        cache.store("/c".to_string(), &[], 200, &headers(&[]), b"c");
+        cache.store(
+            "/d".to_string(),
+            &[],
+            200,
+            &headers(&[("etag", "\"1\"")]),
+            b"d",
+        );
+
+        assert!(cache.get("/a", &[]).unwrap().is_fresh());
+        assert!(cache.get("/b", &[]).is_none());
+        assert!(cache.get("/c", &[]).is_none());
+        let revalidate = cache.get("/d", &[]).unwrap();
+        assert!(!revalidate.is_fresh());
+        assert_eq!(
+            revalidate.validators(),
+            headers(&[("if-none-match", "\"1\"")])
+        );
+    }
+
+    #[test]
+    fn test_evicts_oldest_when_full() {
+        let cache = ResponseCache::new(vec!["/".to_string()], 100, 100);
+        let cache_control = headers(&[("cache-control", "max-age=60")]);
+
+        cache.store("/a".to_string(), &[], 200, &cache_control, &[0; 50]);
+        cache.store("/b".to_string(), &[], 200, &cache_control, &[0; 50]);
+        cache.store("/c".to_string(), &[], 200, &cache_control, &[0; 200]);
+
+        assert!(cache.get("/a", &[]).is_none());
+        assert!(cache.get("/b", &[]).is_some());
+        assert!(cache.get("/c", &[]).is_none());
+    }
+
+    #[test]
+    fn test_encoded_responses_vary_by_accept_encoding() {
+        let cache = ResponseCache::new(vec!["/".to_string()], 1024, 4096);
+        let gzip = headers(&[("accept-encoding", "gzip")]);
+
+        cache.store(
+            "/a".to_string(),
+            &gzip,
+            200,
+            &headers(&[
+                ("cache-control", "max-age=60"),
+                ("content-encoding", "gzip"),
+            ]),
+            b"a",
+        );
+        assert!(cache.get("/a", &gzip).is_none());
+
+        cache.store(
+            "/b".to_string(),
+            &gzip,
+            200,
+            &headers(&[
+                ("cache-control", "max-age=60"),
+                ("content-encoding", "gzip"),
+                ("vary", "Accept-Encoding"),
+            ]),
+            b"b",
+        );
+        assert!(cache.get("/b", &gzip).is_some());
+        assert!(cache.get("/b", &[]).is_none());
+        assert!(
+            cache
+                .get("/b", &headers(&[("accept-encoding", "br")]))
+                .is_none()
+        );
+
+        cache.store(
+            "/c".to_string(),
+            &[],
+            200,
+            &headers(&[("cache-control", "max-age=60"), ("vary", "*")]),
+            b"c",
+        );
+        assert!(cache.get("/c", &[]).is_none());
+    }
+
+    #[test]
+    fn test_revalidates_evicted_entry() {
+        let cache = ResponseCache::new(vec!["/".to_string()], 100, 100);
+        cache.store(
+            "/a".to_string(),
+            &[],
+            200,
+            &headers(&[("etag", "\"1\"")]),
+            &[0; 50],
+        );
+        let cached = cache.get("/a", &[]).unwrap();
+
+        cache.store(
+            "/b".to_string(),
+            &[],
+            200,
+            &headers(&[("cache-control", "max-age=60")]),
+            &[0; 70],
+        );
+        assert!(cache.get("/a", &[]).is_none());
+
+        let entry = cache.revalidated(
+            "/a".to_string(),
+            cached,
+            &headers(&[("cache-control", "max-age=60"), ("etag", "\"1\"")]),
+        );
+        assert_eq!(entry.status, 200);
+        assert_eq!(entry.body, vec![0; 50]);
+        assert!(entry.is_fresh());
+        assert_eq!(
+            entry.headers,
+            headers(&[("cache-control", "max-age=60"), ("etag", "\"1\"")])
+        );
+        assert!(cache.get("/a", &[]).is_some());
+    }
+
+    #[test]
+    fn test_conditional_requests_bypass_cache() {
+        let cache = ResponseCache::new(vec!["/static/".to_string()], 1024, 4096);
+        assert!(cache.is_cacheable_request("GET", "/static/app.js", &[]));
+        assert!(!cache.is_cacheable_request("POST", "/static/app.js", &[]));
+        assert!(!cache.is_cacheable_request("GET", "/auth/token", &[]));
+        assert!(!cache.is_cacheable_request(
+            "GET",
+            "/static/app.js",
+            &headers(&[("If-None-Match", "\"1\"")])
+        ));
+    }
+}
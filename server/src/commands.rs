@@ -0,0 +1,299 @@
+use crate::config::{Config, parse_config};
+use crate::ip_filter::IpFilter;
+use crate::validation::{ALEXA_PATH, GOOGLE_PATH};
+use anyhow::{Context as _, Result, bail};
+use clap::ValueEnum;
+use common::now_as_secs;
+use common::report::Report;
+use common::secrets::weak_secret;
+use common::tunnel::REQUEST_ID_HEADER;
+use reqwest::{Client, StatusCode};
+use serde_json::{Value, json};
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::path::PathBuf;
+use std::time::Instant;
+use uuid::Uuid;
+
+/// Bytes of randomness in a generated secret
+const SECRET_BYTES: usize = 32;
+
+/// Placeholder access token of simulated requests, rejected by Home Assistant
+const SAMPLE_TOKEN: &str = "simulate";
+
+/// Assistant a simulated request comes from
+#[derive(Debug, Clone, Copy, ValueEnum)]
+pub enum Assistant {
+    /// Alexa Smart Home discovery directive
+    Alexa,
+    /// Google Assistant SYNC intent
+    Google,
+}
+
+fn load_config(config_file: PathBuf) -> Result<Config> {
+    parse_config(config_file).context("Invalid configuration")
+}
+
+/// Loopback address for a listener bound to all interfaces
+fn reachable_ip(ip: IpAddr) -> IpAddr {
+    match ip {
+        IpAddr::V4(ip) if ip.is_unspecified() => IpAddr::V4(Ipv4Addr::LOCALHOST),
+        IpAddr::V6(ip) if ip.is_unspecified() => IpAddr::V6(Ipv6Addr::LOCALHOST),
+        ip => ip,
+    }
+}
+
+/// URL the public listener of the running server is reached on
+fn server_url(config: &Config) -> String {
+    match config.host.parse::<IpAddr>() {
+        Ok(ip) => format!("http://{}", SocketAddr::new(reachable_ip(ip), config.port)),
+        Err(_) => format!("http://{}:{}", config.host, config.port),
+    }
+}
+
+/// URL the admin API of the running server is reached on
+fn admin_url(config: &Config) -> String {
+    match config.admin_bind {
+        Some(addr) => format!(
+            "http://{}/admin",
+            SocketAddr::new(reachable_ip(addr.ip()), addr.port())
+        ),
+        None => format!("{}/admin", server_url(config)),
+    }
+}
+
+/// Parses and validates the configuration, including the GeoIP database
+pub fn check_config(config_file: PathBuf) -> Result<()> {
+    let config = load_config(config_file)?;
+    IpFilter::new(config.ip_filters.clone(), config.geoip_database.as_deref())?;
+
+    let mut report = Report::default();
+
+    report.ok("Configuration is valid");
+    report.info(format!("listener: {}:{}", config.host, config.port));
+    report.info(format!(
+        "proxy_mode: {:?}, proxy_protocol: {}",
+        config.proxy_mode, config.proxy_protocol
+    ));
+    report.info(format!(
+        "alexa_validation: {}, google_validation: {}",
+        config.alexa_validation, config.google_validation
+    ));
+    report.info(format!(
+        "rate limits: {}, ip filters: {}, cached routes: {}",
+        config.rate_limits.len(),
+        config.ip_filters.len(),
+        config.cache_routes.len()
+    ));
+    report.info(format!("load_balancing: {:?}", config.load_balancing));
+    match (config.admin_bind, &config.admin_token) {
+        (Some(addr), token) => report.info(format!(
+            "admin API: {} ({})",
+            addr,
+            if token.is_some() { "token" } else { "no token" }
+        )),
+        (None, Some(_)) => report.info("admin API: /admin on the listener"),
+        (None, None) => report.info("admin API: disabled"),
+    }
+
+    if let Some(reason) = weak_secret(&config.secret) {
+        report.fail(format!(
+            "The secret is weak, {}. Generate one with `ha-tunnel-server gen-secret`",
+            reason
+        ));
+    }
+    if let Some(addr) = config.admin_bind
+        && config.admin_token.is_none()
+        && !addr.ip().is_loopback()
+    {
+        report.fail(format!(
+            "Admin API on {} is reachable without a token, set admin_token",
+            addr
+        ));
+    }
+
+    report.finish()
+}
+
+/// Prints a random secret and the matching lines of both configurations
+pub fn gen_secret(server_url: &str) {
+    let secret: String = rand::random::<[u8; SECRET_BYTES]>()
+        .iter()
+        .map(|byte| format!("{:02x}", byte))
+        .collect();
+
+    println!("# Server config.toml");
+    println!("secret = \"{}\"", secret);
+    println!();
+    println!("# Client config.toml");
+    println!("server = \"{}\"", server_url);
+    println!("secret = \"{}\"", secret);
+    println!("ha_server = \"http://localhost:8123\"");
+}
+
+/// Lists the connections of the running server through the admin API
+pub async fn list_clients(
+    config_file: PathBuf,
+    url: Option<String>,
+    token: Option<String>,
+) -> Result<()> {
+    let config = load_config(config_file)?;
+    let url = format!(
+        "{}/clients",
+        url.unwrap_or_else(|| admin_url(&config))
+            .trim_end_matches('/')
+    );
+
+    let mut request = Client::new().get(&url);
+    if let Some(token) = token.or(config.admin_token) {
+        request = request.bearer_auth(token);
+    }
+    let response = request
+        .send()
+        .await
+        .with_context(|| format!("Failed to reach admin API at {}", url))?;
+    match response.status() {
+        StatusCode::OK => {}
+        StatusCode::NOT_FOUND => {
+            bail!("Admin API is disabled, set admin_token or admin_bind")
+        }
+        StatusCode::UNAUTHORIZED => bail!("Admin API rejected the token"),
+        status => bail!("Admin API answered with status {}", status),
+    }
+
+    let connections: Vec<Value> = response.json().await?;
+    if connections.is_empty() {
+        println!("No clients connected");
+        return Ok(());
+    }
+
+    let now = now_as_secs();
+    println!(
+        "{:<24} {:<10} {:<22} {:<10} {:>9} {:>9} {:>8}",
+        "CLIENT", "CONNECTION", "ADDRESS", "VERSION", "CONNECTED", "IN FLIGHT", "RTT"
+    );
+    for connection in &connections {
+        let text = |field: &str| connection[field].as_str().unwrap_or("-").to_string();
+        let connected_at = connection["connected_at"].as_u64().unwrap_or(now);
+        println!(
+            "{:<24} {:<10} {:<22} {:<10} {:>8}s {:>9} {:>8}",
+            text("client_id"),
+            text("connection_id").chars().take(8).collect::<String>(),
+            text("remote_addr"),
+            text("version"),
+            now.saturating_sub(connected_at),
+            connection["in_flight"].as_u64().unwrap_or_default(),
+            connection["rtt_ms"]
+                .as_u64()
+                .map(|rtt| format!("{} ms", rtt))
+                .unwrap_or_else(|| "-".to_string()),
+        );
+    }
+
+    Ok(())
+}
+
+/// Sample request as sent by the assistant's cloud
+fn sample_request(assistant: Assistant, token: &str) -> (&'static str, Value) {
+    match assistant {
+        Assistant::Alexa => (
+            ALEXA_PATH,
+            json!({
+                "directive": {
+                    "header": {
+                        "namespace": "Alexa.Discovery",
+                        "name": "Discover",
+                        "payloadVersion": "3",
+                        "messageId": Uuid::new_v4().to_string(),
+                    },
+                    "payload": {
+                        "scope": {"type": "BearerToken", "token": token},
+                    },
+                },
+            }),
+        ),
+        Assistant::Google => (
+            GOOGLE_PATH,
+            json!({
+                "requestId": Uuid::new_v4().to_string(),
+                "inputs": [{"intent": "action.devices.SYNC"}],
+            }),
+        ),
+    }
+}
+
+/// Sends a sample assistant request to the running server and prints what
+/// came back through the tunnel
+pub async fn simulate(
+    config_file: PathBuf,
+    assistant: Assistant,
+    url: Option<String>,
+    token: Option<String>,
+) -> Result<()> {
+    let config = load_config(config_file)?;
+    let url = url.unwrap_or_else(|| server_url(&config));
+    let token = token.as_deref().unwrap_or(SAMPLE_TOKEN);
+    let (path, body) = sample_request(assistant, token);
+    let url = format!("{}{}", url.trim_end_matches('/'), path);
+
+    println!("POST {}", url);
+    let start = Instant::now();
+    let response = Client::new()
+        .post(&url)
+        .bearer_auth(token)
+        .json(&body)
+        .send()
+        .await
+        .with_context(|| format!("Failed to reach server at {}", url))?;
+    let latency_ms = start.elapsed().as_millis();
+
+    let status = response.status();
+    let request_id = response
+        .headers()
+        .get(REQUEST_ID_HEADER)
+        .and_then(|value| value.to_str().ok())
+        .unwrap_or("-")
+        .to_string();
+    let text = response.text().await.unwrap_or_default();
+    println!(
+        "{} in {} ms (request ID {})",
+        status, latency_ms, request_id
+    );
+    match serde_json::from_str::<Value>(&text) {
+        Ok(json) => println!("{}", serde_json::to_string_pretty(&json)?),
+        Err(_) if !text.is_empty() => println!("{}", text),
+        Err(_) => {}
+    }
+
+    match status {
+        StatusCode::SERVICE_UNAVAILABLE => bail!("No client is connected"),
+        StatusCode::BAD_GATEWAY | StatusCode::GATEWAY_TIMEOUT => {
+            bail!("The request failed between server and Home Assistant")
+        }
+        StatusCode::UNAUTHORIZED if token == SAMPLE_TOKEN => {
+            println!(
+                "The tunnel works, Home Assistant rejected the sample token. Pass --token with a long-lived access token for a real answer."
+            );
+            Ok(())
+        }
+        status if status.is_success() => Ok(()),
+        status => bail!("Request failed with status {}", status),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::validation::{validate_alexa_request, validate_google_request};
+
+    #[test]
+    fn test_sample_requests_validate() {
+        let (_, alexa) = sample_request(Assistant::Alexa, "token");
+        assert!(validate_alexa_request(Some(alexa.to_string().as_bytes()), 65536).is_ok());
+
+        let (_, google) = sample_request(Assistant::Google, "token");
+        let headers = vec![("authorization".to_string(), "Bearer token".to_string())];
+        assert!(
+            validate_google_request(&headers, Some(google.to_string().as_bytes()), 65536).is_ok()
+        );
+    }
+}
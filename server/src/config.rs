@@ -1,8 +1,15 @@
+use crate::balancer::LoadBalancing;
+use crate::ip_filter::IpFilterRule;
+use crate::rate_limit::RateLimitRule;
 use anyhow::Result;
+use common::access_log::AccessLogFormat;
+use common::compression::Compression;
+use common::logging::{LogFormat, build_filter};
+use common::secrets::resolve_file_keys;
 use config::Config as ConfigParser;
-use std::net::IpAddr;
+use std::net::{IpAddr, SocketAddr};
 use std::path::PathBuf;
-use tracing::Level;
+use tracing::{Level, warn};
 
 #[derive(Debug, Clone, Default)]
 pub enum ProxyMode {
@@ -37,8 +44,22 @@ impl ProxyMode {
     }
 }
 
+#[derive(Clone)]
 pub struct Config {
     pub log_level: Level,
+    /// `EnvFilter` directives overriding `log_level` for single targets
+    pub log_filter: String,
+    pub log_format: LogFormat,
+    /// OTLP/HTTP collector spans are exported to (None = disabled)
+    pub otlp_endpoint: Option<String>,
+
+    /// Access log target, `stdout` or a file path (None = disabled)
+    pub access_log: Option<String>,
+    pub access_log_format: AccessLogFormat,
+    /// Size in MB after which the access log file is rotated
+    pub access_log_max_size: u64,
+    /// Rotated access log files kept
+    pub access_log_max_files: usize,
 
     pub host: String,
     pub port: u16,
@@ -47,27 +68,177 @@ pub struct Config {
 
     pub client_timeout: u64,
     pub request_timeout: u64,
+    /// Seconds in-flight requests get to complete on shutdown
+    pub shutdown_grace_period: u64,
 
     /// Proxy mode for extracting real client IP
     pub proxy_mode: ProxyMode,
     /// List of trusted proxy IPs/networks. If empty, all proxies are trusted.
     pub trusted_proxies: Vec<IpAddr>,
+    /// Expect a PROXY protocol (v1/v2) header on every incoming connection
+    pub proxy_protocol: bool,
+
+    /// Rate limit rules applied to the public API routes
+    pub rate_limits: Vec<RateLimitRule>,
+
+    /// IP allow/deny rules applied to the public API routes
+    pub ip_filters: Vec<IpFilterRule>,
+    /// Path to a MaxMind-format country database used for country filters
+    pub geoip_database: Option<PathBuf>,
+
+    /// Validate Alexa Smart Home directives before tunneling them
+    pub alexa_validation: bool,
+    /// Maximum accepted size of an Alexa directive in bytes
+    pub alexa_max_body_size: usize,
+    /// Validate Google Assistant fulfillment requests before tunneling them
+    pub google_validation: bool,
+    /// Maximum accepted size of a Google Assistant request in bytes
+    pub google_max_body_size: usize,
+
+    /// Path prefixes of GET routes whose responses are cached (empty = disabled)
+    pub cache_routes: Vec<String>,
+    /// Maximum size of a single cached response in bytes
+    pub cache_max_entry_size: usize,
+    /// Maximum total size of the response cache in bytes
+    pub cache_max_size: usize,
+
+    /// Body compressions the server accepts for tunnel traffic (empty = disabled)
+    pub tunnel_compression: Vec<Compression>,
+
+    /// How requests are spread across the connected clients
+    pub load_balancing: LoadBalancing,
+
+    /// Separate address the admin API is served on (None = public listener)
+    pub admin_bind: Option<SocketAddr>,
+    /// Bearer token required by the admin API
+    pub admin_token: Option<String>,
+    /// Client IDs that are rejected at authentication
+    pub revoked_clients: Vec<String>,
+}
+
+impl Config {
+    /// Takes the settings that can change at runtime from a newly parsed
+    /// configuration. Changes to the remaining settings are logged and only
+    /// take effect after a restart.
+    pub fn reload(&self, new: Config) -> Config {
+        let restart_required = [
+            ("host", self.host != new.host),
+            ("port", self.port != new.port),
+            ("proxy_protocol", self.proxy_protocol != new.proxy_protocol),
+            ("rate_limits", self.rate_limits != new.rate_limits),
+            ("ip_filters", self.ip_filters != new.ip_filters),
+            ("geoip_database", self.geoip_database != new.geoip_database),
+            ("cache_routes", self.cache_routes != new.cache_routes),
+            (
+                "cache_max_entry_size",
+                self.cache_max_entry_size != new.cache_max_entry_size,
+            ),
+            ("cache_max_size", self.cache_max_size != new.cache_max_size),
+            ("log_format", self.log_format != new.log_format),
+            ("otlp_endpoint", self.otlp_endpoint != new.otlp_endpoint),
+            ("admin_bind", self.admin_bind != new.admin_bind),
+            // Without `admin_bind` the API is only mounted on the public
+            // listener at startup if a token is set
+            (
+                "admin_token",
+                self.admin_bind.is_none()
+                    && self.admin_token.is_some() != new.admin_token.is_some(),
+            ),
+            ("access_log", self.access_log != new.access_log),
+            (
+                "access_log_format",
+                self.access_log_format != new.access_log_format,
+            ),
+            (
+                "access_log_max_size",
+                self.access_log_max_size != new.access_log_max_size,
+            ),
+            (
+                "access_log_max_files",
+                self.access_log_max_files != new.access_log_max_files,
+            ),
+        ];
+        for (setting, changed) in restart_required {
+            if changed {
+                warn!(
+                    setting = setting,
+                    "Setting changed, restart required to apply it"
+                );
+            }
+        }
+
+        Config {
+            host: self.host.clone(),
+            port: self.port,
+            proxy_protocol: self.proxy_protocol,
+            rate_limits: self.rate_limits.clone(),
+            ip_filters: self.ip_filters.clone(),
+            geoip_database: self.geoip_database.clone(),
+            cache_routes: self.cache_routes.clone(),
+            cache_max_entry_size: self.cache_max_entry_size,
+            cache_max_size: self.cache_max_size,
+            log_format: self.log_format,
+            otlp_endpoint: self.otlp_endpoint.clone(),
+            admin_bind: self.admin_bind,
+            access_log: self.access_log.clone(),
+            access_log_format: self.access_log_format,
+            access_log_max_size: self.access_log_max_size,
+            access_log_max_files: self.access_log_max_files,
+            ..new
+        }
+    }
 }
 
 pub fn parse_config(config_file: PathBuf) -> Result<Config> {
     let settings = ConfigParser::builder()
         .set_default("log_level", "INFO")?
+        .set_default("log_filter", "")?
+        .set_default("log_format", "full")?
+        .set_default("access_log", "off")?
+        .set_default("access_log_format", "json")?
+        .set_default("access_log_max_size", 10)?
+        .set_default("access_log_max_files", 5)?
         .set_default("host", "0.0.0.0")?
         .set_default("port", 3000)?
         .set_default("client_timeout", 10)?
         .set_default("request_timeout", 30)?
+        .set_default("shutdown_grace_period", 30)?
         .set_default("proxy_mode", "none")?
         .set_default::<&str, Vec<String>>("trusted_proxies", vec![])?
+        .set_default("proxy_protocol", false)?
+        .set_default::<&str, Vec<String>>("rate_limits", vec![])?
+        .set_default::<&str, Vec<String>>("ip_filters", vec![])?
+        .set_default("alexa_validation", false)?
+        .set_default("alexa_max_body_size", 64 * 1024)?
+        .set_default("google_validation", false)?
+        .set_default("google_max_body_size", 64 * 1024)?
+        .set_default::<&str, Vec<String>>("cache_routes", vec![])?
+        .set_default("cache_max_entry_size", 1024 * 1024)?
+        .set_default("cache_max_size", 64 * 1024 * 1024)?
+        .set_default("tunnel_compression", vec!["zstd", "gzip"])?
+        .set_default("load_balancing", "round_robin")?
+        .set_default::<&str, Vec<String>>("revoked_clients", vec![])?
         .add_source(config::File::with_name(config_file.to_str().unwrap()).required(false))
         .add_source(config::Environment::with_prefix("HA_TUNNEL"))
         .build()?;
+    let settings = resolve_file_keys(settings, &[])?;
 
     let log_level = settings.get_string("log_level")?.parse()?;
+    let log_filter = settings.get_string("log_filter")?;
+    build_filter(log_level, &log_filter)?;
+    let log_format = settings.get_string("log_format")?.parse()?;
+    let otlp_endpoint = settings
+        .get_string("otlp_endpoint")
+        .ok()
+        .filter(|endpoint| !endpoint.is_empty());
+
+    let access_log = settings
+        .get_string("access_log")
+        .ok()
+        .filter(|target| !target.is_empty() && target != "off");
+    let access_log_format = settings.get_string("access_log_format")?.parse()?;
+    let access_log_max_size = settings.get_int("access_log_max_size")?.try_into()?;
+    let access_log_max_files = settings.get_int("access_log_max_files")?.try_into()?;
     let host = settings.get_string("host")?;
     let port = settings.get_int("port")?.try_into()?;
 
@@ -75,6 +246,7 @@ pub fn parse_config(config_file: PathBuf) -> Result<Config> {
 
     let client_timeout = settings.get_int("client_timeout")?.try_into()?;
     let request_timeout = settings.get_int("request_timeout")?.try_into()?;
+    let shutdown_grace_period = settings.get_int("shutdown_grace_period")?.try_into()?;
 
     let proxy_mode = parse_proxy_mode(&settings.get_string("proxy_mode")?);
     let trusted_proxies = settings
@@ -83,9 +255,54 @@ pub fn parse_config(config_file: PathBuf) -> Result<Config> {
         .into_iter()
         .filter_map(|v| v.into_string().ok()?.parse::<IpAddr>().ok())
         .collect();
+    let proxy_protocol = settings.get_bool("proxy_protocol")?;
+
+    let rate_limits = settings.get::<Vec<RateLimitRule>>("rate_limits")?;
+
+    let ip_filters = settings.get::<Vec<IpFilterRule>>("ip_filters")?;
+    let geoip_database = settings
+        .get_string("geoip_database")
+        .ok()
+        .map(PathBuf::from);
+
+    let alexa_validation = settings.get_bool("alexa_validation")?;
+    let alexa_max_body_size = settings.get_int("alexa_max_body_size")?.try_into()?;
+    let google_validation = settings.get_bool("google_validation")?;
+    let google_max_body_size = settings.get_int("google_max_body_size")?.try_into()?;
+
+    let cache_routes = settings.get::<Vec<String>>("cache_routes")?;
+    let cache_max_entry_size = settings.get_int("cache_max_entry_size")?.try_into()?;
+    let cache_max_size = settings.get_int("cache_max_size")?.try_into()?;
+
+    let tunnel_compression = settings
+        .get::<Vec<String>>("tunnel_compression")?
+        .iter()
+        .map(|c| c.parse())
+        .collect::<Result<_, _>>()?;
+
+    let load_balancing = settings.get_string("load_balancing")?.parse()?;
+
+    let admin_bind = settings
+        .get_string("admin_bind")
+        .ok()
+        .map(|bind| bind.parse())
+        .transpose()?;
+    let admin_token = settings
+        .get_string("admin_token")
+        .ok()
+        .filter(|token| !token.is_empty());
+    let revoked_clients = settings.get::<Vec<String>>("revoked_clients")?;
 
     Ok(Config {
         log_level,
+        log_filter,
+        log_format,
+        otlp_endpoint,
+
+        access_log,
+        access_log_format,
+        access_log_max_size,
+        access_log_max_files,
 
         host,
         port,
@@ -94,9 +311,33 @@ pub fn parse_config(config_file: PathBuf) -> Result<Config> {
 
         client_timeout,
         request_timeout,
+        shutdown_grace_period,
 
         proxy_mode,
         trusted_proxies,
+        proxy_protocol,
+
+        rate_limits,
+
+        ip_filters,
+        geoip_database,
+
+        alexa_validation,
+        alexa_max_body_size,
+        google_validation,
+        google_max_body_size,
+
+        cache_routes,
+        cache_max_entry_size,
+        cache_max_size,
+
+        tunnel_compression,
+
+        load_balancing,
+
+        admin_bind,
+        admin_token,
+        revoked_clients,
     })
 }
 
@@ -0,0 +1,174 @@
+use anyhow::{Context, Result, bail};
+use common::error::ProxyError;
+use ipnet::IpNet;
+use maxminddb::{Reader, geoip2};
+use serde::Deserialize;
+use std::net::IpAddr;
+use std::path::Path;
+use tracing::debug;
+
+/// A single filter rule as configured under `[[ip_filters]]`
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+pub struct IpFilterRule {
+    /// Request path the rule applies to, or `*` for all API routes
+    pub route: String,
+    /// If non-empty, only requests from these networks are allowed
+    #[serde(default)]
+    pub allow: Vec<IpNet>,
+    /// Requests from these networks are always denied
+    #[serde(default)]
+    pub deny: Vec<IpNet>,
+    /// If non-empty, only requests from these countries (ISO codes) are allowed
+    #[serde(default)]
+    pub allow_countries: Vec<String>,
+    /// Requests from these countries (ISO codes) are always denied
+    #[serde(default)]
+    pub deny_countries: Vec<String>,
+}
+
+impl IpFilterRule {
+    fn matches(&self, path: &str) -> bool {
+        self.route == "*" || self.route == path
+    }
+
+    fn uses_countries(&self) -> bool {
+        !self.allow_countries.is_empty() || !self.deny_countries.is_empty()
+    }
+}
+
+/// Checks requests against the configured allow/deny lists before they are
+/// sent through the tunnel
+pub struct IpFilter {
+    rules: Vec<IpFilterRule>,
+    geoip: Option<Reader<Vec<u8>>>,
+}
+
+impl IpFilter {
+    pub fn new(rules: Vec<IpFilterRule>, geoip_database: Option<&Path>) -> Result<Self> {
+        let geoip = match geoip_database {
+            Some(path) => Some(
+                Reader::open_readfile(path)
+                    .with_context(|| format!("Failed to open GeoIP database {:?}", path))?,
+            ),
+            None => None,
+        };
+
+        if geoip.is_none() && rules.iter().any(IpFilterRule::uses_countries) {
+            bail!("ip_filters use country lists but no geoip_database is configured");
+        }
+
+        Ok(Self { rules, geoip })
+    }
+
+    /// Returns `ProxyError::FilterDenied` if any rule matching the path
+    /// rejects the source IP
+    pub fn check(&self, path: &str, source_ip: &str) -> Result<(), ProxyError> {
+        let rules: Vec<_> = self.rules.iter().filter(|r| r.matches(path)).collect();
+        if rules.is_empty() {
+            return Ok(());
+        }
+
+        let ip: IpAddr = source_ip
+            .parse()
+            .map_err(|_| ProxyError::FilterDenied(format!("Invalid source IP {}", source_ip)))?;
+
+        let country = if rules.iter().any(|r| r.uses_countries()) {
+            self.lookup_country(ip)
+        } else {
+            None
+        };
+
+        for rule in rules {
+            if rule.deny.iter().any(|net| net.contains(&ip)) {
+                return Err(ProxyError::FilterDenied(format!("{} is denied", ip)));
+            }
+            if !rule.allow.is_empty() && !rule.allow.iter().any(|net| net.contains(&ip)) {
+                return Err(ProxyError::FilterDenied(format!("{} is not allowed", ip)));
+            }
+
+            if let Some(country) = &country
+                && rule
+                    .deny_countries
+                    .iter()
+                    .any(|c| c.eq_ignore_ascii_case(country))
+            {
+                return Err(ProxyError::FilterDenied(format!(
+                    "Country {} is denied",
+                    country
+                )));
+            }
+            if !rule.allow_countries.is_empty()
+                && !country.as_ref().is_some_and(|country| {
+                    rule.allow_countries
+                        .iter()
+                        .any(|c| c.eq_ignore_ascii_case(country))
+                })
+            {
+                return Err(ProxyError::FilterDenied(format!(
+                    "Country {} is not allowed",
+                    country.as_deref().unwrap_or("unknown")
+                )));
+            }
+        }
+
+        Ok(())
+    }
+
+    fn lookup_country(&self, ip: IpAddr) -> Option<String> {
+        let reader = self.geoip.as_ref()?;
+        match reader.lookup::<geoip2::Country>(ip) {
+            Ok(record) => record
+                .country
+                .and_then(|c| c.iso_code)
+                .map(|code| code.to_string()),
+            Err(e) => {
+                debug!(ip = %ip, error = %e, "GeoIP lookup failed");
+                None
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn filter(allow: &[&str], deny: &[&str]) -> IpFilter {
+        let rule = IpFilterRule {
+            route: "/api/alexa/smart_home".to_string(),
+            allow: allow.iter().map(|n| n.parse().unwrap()).collect(),
+            deny: deny.iter().map(|n| n.parse().unwrap()).collect(),
+            allow_countries: vec![],
+            deny_countries: vec![],
+        };
+        IpFilter::new(vec![rule], None).unwrap()
+    }
+
+    #[test]
+    fn test_allow_list() {
+        let filter = filter(&["54.239.0.0/16", "2001:db8::/32"], &[]);
+        assert!(filter.check("/api/alexa/smart_home", "54.239.1.2").is_ok());
+        assert!(filter.check("/api/alexa/smart_home", "2001:db8::1").is_ok());
+        assert!(filter.check("/api/alexa/smart_home", "1.2.3.4").is_err());
+        assert!(filter.check("/api/google_assistant", "1.2.3.4").is_ok());
+    }
+
+    #[test]
+    fn test_deny_wins_over_allow() {
+        let filter = filter(&["10.0.0.0/8"], &["10.1.0.0/16"]);
+        assert!(filter.check("/api/alexa/smart_home", "10.2.0.1").is_ok());
+        assert!(filter.check("/api/alexa/smart_home", "10.1.0.1").is_err());
+    }
+
+    #[test]
+    fn test_countries_require_database() {
+        let rule = IpFilterRule {
+            route: "*".to_string(),
+            allow: vec![],
+            deny: vec![],
+            allow_countries: vec!["DE".to_string()],
+            deny_countries: vec![],
+        };
+        assert!(IpFilter::new(vec![rule], None).is_err());
+    }
+}
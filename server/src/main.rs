@@ -1,38 +1,144 @@
+mod admin;
 mod auth;
+mod balancer;
+mod cache;
 mod client_ip;
+mod commands;
 mod config;
+mod dashboard;
+mod ip_filter;
 mod proxy;
+mod proxy_protocol;
+mod rate_limit;
+mod stats;
+mod validation;
 
+use crate::admin::{close_client_connections, create_admin_router};
+use crate::cache::ResponseCache;
+use crate::commands::Assistant;
 use crate::config::{Config, parse_config};
-use crate::proxy::{ClientConnection, create_router};
+use crate::ip_filter::IpFilter;
+use crate::proxy::{ClientConnection, PendingRequest, announce_shutdown, create_router};
+use crate::proxy_protocol::ProxyProtocolListener;
+use crate::rate_limit::{CLEANUP_INTERVAL, RateLimiter};
+use crate::stats::RequestStats;
 use anyhow::Result;
-use clap::Parser;
-use common::tunnel::TunnelMessage;
-use dashmap::DashMap;
+use axum::Router;
+use axum::serve::ListenerExt;
+use clap::{Parser, Subcommand};
+use common::access_log::AccessLog;
+use common::logging::{LogFilterHandle, init_logging, reload_log_filter};
+use common::reload::watch_config;
+use common::secrets::warn_weak_secret;
+use dashmap::{DashMap, DashSet};
 use std::net::SocketAddr;
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
 use std::sync::Arc;
+use std::sync::atomic::AtomicUsize;
+use std::time::Duration;
 use tokio::signal;
-use tokio::sync::{oneshot, watch};
-use tracing::info;
+use tokio::sync::watch;
+use tracing::{error, info, warn};
 
 #[derive(Parser, Debug)]
 struct Args {
     /// Path to configuration file
-    #[arg(short, long, default_value = "config.toml")]
+    #[arg(short, long, default_value = "config.toml", global = true)]
     config: PathBuf,
+
+    #[command(subcommand)]
+    command: Option<Command>,
+}
+
+#[derive(Subcommand, Debug)]
+enum Command {
+    /// Parses and validates the configuration
+    CheckConfig,
+    /// Generates a random secret and prints the matching client configuration
+    GenSecret {
+        /// Public URL of this server, as clients connect to it
+        #[arg(long, default_value = "https://your-server.example.com")]
+        server_url: String,
+    },
+    /// Lists the clients connected to the running server through the admin API
+    ListClients {
+        /// Admin API URL (default: derived from `admin_bind` or `host` and `port`)
+        #[arg(long)]
+        url: Option<String>,
+        /// Admin token (default: `admin_token` of the configuration)
+        #[arg(long)]
+        token: Option<String>,
+    },
+    /// Sends a sample assistant request to the running server and prints the tunneled result
+    Simulate {
+        #[arg(value_enum)]
+        assistant: Assistant,
+        /// Server URL (default: derived from `host` and `port`)
+        #[arg(long)]
+        url: Option<String>,
+        /// Home Assistant access token sent with the request
+        #[arg(long)]
+        token: Option<String>,
+    },
+}
+
+async fn run_command(command: Command, config_file: PathBuf) -> Result<()> {
+    match command {
+        Command::CheckConfig => commands::check_config(config_file),
+        Command::GenSecret { server_url } => {
+            commands::gen_secret(&server_url);
+            Ok(())
+        }
+        Command::ListClients { url, token } => {
+            commands::list_clients(config_file, url, token).await
+        }
+        Command::Simulate {
+            assistant,
+            url,
+            token,
+        } => commands::simulate(config_file, assistant, url, token).await,
+    }
 }
 
 struct ServerState {
-    config: Config,
-    /// Connected clients indexed by client_id
+    /// Current configuration, replaced on reload
+    config: watch::Sender<Arc<Config>>,
+    /// Open tunnel connections indexed by connection_id, a client may have several
     clients: DashMap<String, ClientConnection>,
+    /// Counts requests to rotate between equally suited connections
+    next_connection: AtomicUsize,
     /// Pending requests waiting for responses
-    pending_requests: DashMap<String, oneshot::Sender<TunnelMessage>>,
+    pending_requests: DashMap<String, PendingRequest>,
     /// Notifier for when clients connect (sender side)
     client_connected_tx: watch::Sender<usize>,
     /// Notifier for when clients connect (receiver side, clone this to wait)
     client_connected_rx: watch::Receiver<usize>,
+    /// Rate limiter for the public API routes
+    rate_limiter: RateLimiter,
+    /// IP allow/deny filter for the public API routes
+    ip_filter: IpFilter,
+    /// Cache for responses to idempotent GET requests
+    response_cache: ResponseCache,
+    /// Client IDs revoked through the admin API
+    revoked_clients: DashSet<String>,
+    /// Answered API requests for the dashboard
+    request_stats: RequestStats,
+    access_log: AccessLog,
+}
+
+impl ServerState {
+    fn config(&self) -> Arc<Config> {
+        self.config.borrow().clone()
+    }
+
+    fn is_revoked(&self, client_id: &str) -> bool {
+        self.revoked_clients.contains(client_id)
+            || self
+                .config()
+                .revoked_clients
+                .iter()
+                .any(|revoked| revoked == client_id)
+    }
 }
 
 async fn shutdown_signal() {
@@ -61,17 +167,91 @@ async fn shutdown_signal() {
     info!("Shutdown signal received, starting graceful shutdown");
 }
 
+/// Parses the configuration file again and swaps in the settings that can
+/// change at runtime, disconnecting newly revoked clients. An invalid
+/// configuration is rejected as a whole and the running one kept.
+fn reload_config(state: &ServerState, config_file: &Path, log_filter: &LogFilterHandle) {
+    let new_config = match parse_config(config_file.to_path_buf()) {
+        Ok(new_config) => new_config,
+        Err(e) => {
+            error!(error = %e, "Invalid configuration, keeping the current one");
+            return;
+        }
+    };
+
+    let current = state.config();
+    if new_config.secret != current.secret {
+        warn_weak_secret(&new_config.secret);
+    }
+    let config = current.reload(new_config);
+    let revoked: Vec<String> = config
+        .revoked_clients
+        .iter()
+        .filter(|client_id| !current.revoked_clients.contains(client_id))
+        .cloned()
+        .collect();
+    reload_log_filter(log_filter, config.log_level, &config.log_filter);
+    state.config.send_replace(Arc::new(config));
+
+    info!("Configuration reloaded");
+    for client_id in revoked {
+        let count = close_client_connections(state, &client_id);
+        info!(client_id = %client_id, connections = count, "Client revoked by configuration");
+    }
+}
+
+/// Resolves once shutdown was requested, after telling the connected
+/// clients that the server is going away
+async fn drain_signal(state: Arc<ServerState>, mut shutdown_rx: watch::Receiver<bool>) {
+    let _ = shutdown_rx.wait_for(|shutdown| *shutdown).await;
+    announce_shutdown(&state).await;
+}
+
+/// Runs the server until all in-flight requests completed after a shutdown
+/// signal, or until the grace period expired
+async fn run_with_grace_period(
+    server: impl Future<Output = std::io::Result<()>>,
+    state: &Arc<ServerState>,
+    mut shutdown_rx: watch::Receiver<bool>,
+) -> Result<()> {
+    let grace_period = Duration::from_secs(state.config().shutdown_grace_period);
+    let grace_expired = async {
+        let _ = shutdown_rx.wait_for(|shutdown| *shutdown).await;
+        tokio::time::sleep(grace_period).await;
+    };
+
+    tokio::select! {
+        result = server => result?,
+        _ = grace_expired => {
+            warn!(
+                pending_requests = state.pending_requests.len(),
+                "Shutdown grace period expired, dropping requests in flight"
+            );
+        }
+    }
+
+    Ok(())
+}
+
 #[tokio::main]
 async fn main() -> Result<()> {
     let args = Args::parse();
-    let config = parse_config(args.config)?;
+    if let Some(command) = args.command {
+        return run_command(command, args.config).await;
+    }
+
+    let config = parse_config(args.config.clone())?;
 
-    tracing_subscriber::fmt()
-        .with_max_level(config.log_level)
-        .with_target(false)
-        .init();
+    let (log_filter, _telemetry) = init_logging(
+        config.log_level,
+        &config.log_filter,
+        config.log_format,
+        config.otlp_endpoint.as_deref(),
+        "ha-tunnel-server",
+    )?;
 
     info!("Starting Home Assistant Tunnel Server");
+    warn_weak_secret(&config.secret);
 
     let (client_connected_tx, client_connected_rx) = watch::channel(0usize);
 
@@ -79,22 +259,108 @@ async fn main() -> Result<()> {
 
     info!("Server listening on {}", addr);
 
+    let rate_limiter = RateLimiter::new(config.rate_limits.clone());
+    let ip_filter = IpFilter::new(config.ip_filters.clone(), config.geoip_database.as_deref())?;
+    let response_cache = ResponseCache::new(
+        config.cache_routes.clone(),
+        config.cache_max_entry_size,
+        config.cache_max_size,
+    );
+
+    let access_log = AccessLog::open(
+        config.access_log.as_deref(),
+        config.access_log_format,
+        config.access_log_max_size * 1024 * 1024,
+        config.access_log_max_files,
+    )?;
+
     let state = Arc::new(ServerState {
-        config,
+        config: watch::Sender::new(Arc::new(config)),
         clients: DashMap::new(),
+        next_connection: AtomicUsize::new(0),
         pending_requests: DashMap::new(),
         client_connected_tx,
         client_connected_rx,
+        rate_limiter,
+        ip_filter,
+        response_cache,
+        revoked_clients: DashSet::new(),
+        request_stats: RequestStats::new(),
+        access_log,
     });
-    let app = create_router(state.clone());
+
+    if state.rate_limiter.is_enabled() {
+        let cleanup_state = state.clone();
+        tokio::spawn(async move {
+            let mut interval = tokio::time::interval(CLEANUP_INTERVAL);
+            loop {
+                interval.tick().await;
+                cleanup_state.rate_limiter.cleanup();
+            }
+        });
+    }
+
+    // Reload on SIGHUP and config file changes
+    let reload_state = state.clone();
+    let mut reload_rx = watch_config(args.config.clone());
+    tokio::spawn(async move {
+        while reload_rx.recv().await.is_some() {
+            reload_config(&reload_state, &args.config, &log_filter);
+        }
+    });
+
+    let mut app = create_router(state.clone());
+
+    // Flips to true once a shutdown signal was received
+    let (shutdown_tx, shutdown_rx) = watch::channel(false);
+    tokio::spawn(async move {
+        shutdown_signal().await;
+        let _ = shutdown_tx.send(true);
+    });
+
+    // The admin API gets its own listener if configured, otherwise it is
+    // only reachable on the public one with a token
+    if let Some(admin_addr) = state.config().admin_bind {
+        if state.config().admin_token.is_none() && !admin_addr.ip().is_loopback() {
+            warn!(addr = %admin_addr, "Admin API is reachable without a token");
+        }
+        let admin = Router::new().nest("/admin", create_admin_router(state.clone(), false));
+        let admin_listener = tokio::net::TcpListener::bind(admin_addr).await?;
+        let mut admin_shutdown_rx = shutdown_rx.clone();
+        info!("Admin API listening on {}", admin_addr);
+        tokio::spawn(async move {
+            let server = axum::serve(admin_listener, admin).with_graceful_shutdown(async move {
+                let _ = admin_shutdown_rx.wait_for(|shutdown| *shutdown).await;
+            });
+            if let Err(e) = server.await {
+                error!(error = %e, "Admin API server failed");
+            }
+        });
+    } else if state.config().admin_token.is_some() {
+        info!("Admin API enabled on /admin");
+        app = app.nest("/admin", create_admin_router(state.clone(), true));
+    }
 
     let listener = tokio::net::TcpListener::bind(addr).await?;
-    axum::serve(
-        listener,
-        app.into_make_service_with_connect_info::<SocketAddr>(),
-    )
-    .with_graceful_shutdown(shutdown_signal())
-    .await?;
+    if state.config().proxy_protocol {
+        info!("PROXY protocol enabled on listener");
+        let listener = ProxyProtocolListener::new(listener, state.config.subscribe())?
+            // No-op tap so axum provides `ConnectInfo` for our custom listener
+            .tap_io(|_| {});
+        let server = axum::serve(
+            listener,
+            app.into_make_service_with_connect_info::<SocketAddr>(),
+        )
+        .with_graceful_shutdown(drain_signal(state.clone(), shutdown_rx.clone()));
+        run_with_grace_period(server.into_future(), &state, shutdown_rx).await?;
+    } else {
+        let server = axum::serve(
+            listener,
+            app.into_make_service_with_connect_info::<SocketAddr>(),
+        )
+        .with_graceful_shutdown(drain_signal(state.clone(), shutdown_rx.clone()));
+        run_with_grace_period(server.into_future(), &state, shutdown_rx).await?;
+    }
 
     info!("Server shut down gracefully");
 
@@ -1,46 +1,119 @@
 use crate::ServerState;
 use crate::auth::verify_auth_signature;
+use crate::balancer::{Candidate, LoadBalancing};
+use crate::cache::ResponseCache;
 use crate::client_ip::extract_client_ip;
+use crate::rate_limit::RateLimitKey;
+use crate::stats::RequestRecord;
+use crate::validation::{ALEXA_PATH, GOOGLE_PATH, validate_alexa_request, validate_google_request};
 use axum::Router;
-use axum::body::Body;
+use axum::body::{Body, HttpBody};
 use axum::extract::{ConnectInfo, State, WebSocketUpgrade};
-use axum::http::{HeaderMap, Request, StatusCode};
+use axum::http::{HeaderMap, HeaderValue, Request, StatusCode};
+use axum::middleware::{self, Next};
 use axum::response::{IntoResponse, Response};
 use axum::routing::{get, post};
+use chrono::Utc;
+use common::access_log::AccessLogEntry;
+use common::compression::{Compression, compress_body, decompress_body, negotiate};
+use common::error::ProxyError;
 use common::now_as_secs;
-use common::tunnel::TunnelMessage;
+use common::telemetry::{inject_context, set_parent_from_headers};
+use common::tunnel::{MAX_BODY_SIZE, REQUEST_ID_HEADER, TunnelMessage};
 use futures_util::{SinkExt, StreamExt};
-use std::collections::HashSet;
+use std::collections::{HashMap, HashSet};
 use std::net::SocketAddr;
 use std::sync::Arc;
-use std::time::Duration;
-use tokio::sync::{mpsc, oneshot};
+use std::sync::atomic::Ordering;
+use std::time::{Duration, Instant};
+use tokio::sync::{Notify, mpsc, oneshot};
 use tower_http::trace::TraceLayer;
-use tracing::{debug, error, info, warn};
+use tracing::{Instrument, Span, debug, error, field, info, info_span, warn};
 use uuid::Uuid;
 
 /// Maximum number of retry attempts when sending a request to a client fails
 const MAX_REQUEST_RETRIES: u32 = 3;
 
+/// Interval at which a draining client is checked for requests in flight
+const DRAIN_POLL_INTERVAL: Duration = Duration::from_millis(100);
+
+/// Interval at which the server pings every connection to measure its round trip time
+const RTT_PROBE_INTERVAL: Duration = Duration::from_secs(15);
+
 #[derive(Debug, Clone)]
 pub struct ClientConnection {
-    #[allow(dead_code)]
+    /// Unique per WebSocket, a client may keep several connections
+    pub connection_id: String,
     pub client_id: String,
-    #[allow(dead_code)]
     pub connected_at: u64,
     pub last_ping: u64,
     pub sender: mpsc::Sender<TunnelMessage>,
+    /// Body compression negotiated with this client
+    pub compression: Option<Compression>,
+    /// Priority and weight announced by the client at auth
+    pub priority: u32,
+    pub weight: u32,
+    /// Round trip time of the last answered server ping
+    pub rtt: Option<Duration>,
+    /// When the unanswered server ping was sent
+    pub ping_sent_at: Option<Instant>,
+    pub remote_addr: SocketAddr,
+    /// Client version announced at auth
+    pub version: Option<String>,
+    /// Notified to close the connection
+    pub close: Arc<Notify>,
+}
+
+/// A request waiting for its response from a client
+#[derive(Debug)]
+pub struct PendingRequest {
+    pub client_id: String,
+    pub connection_id: String,
+    pub method: String,
+    pub path: String,
+    pub started_at: Instant,
+    pub sender: oneshot::Sender<TunnelMessage>,
+}
+
+/// Longest `X-Request-Id` accepted from callers, longer ones are replaced
+const MAX_REQUEST_ID_LENGTH: usize = 128;
+
+/// Request extension with the ID of the request, stable across retries
+#[derive(Debug, Clone)]
+struct RequestId(String);
+
+/// Response extension describing how a tunneled request was answered
+#[derive(Debug, Clone)]
+struct Tunneled {
+    client_id: String,
+    tunnel_ms: u64,
+    upstream_ms: Option<u64>,
 }
 
 pub fn create_router(state: Arc<ServerState>) -> Router {
     Router::new()
-        // Tunnel endpoint (WebSocket)
-        .route("/tunnel", get(handle_tunnel_connection))
         // API endpoints
-        .route("/api/alexa/smart_home", post(handle_api_request))
-        .route("/api/google_assistant", post(handle_api_request))
+        .route(ALEXA_PATH, post(handle_api_request))
+        .route(GOOGLE_PATH, post(handle_api_request))
         .route("/auth/authorize", get(handle_api_request))
         .route("/auth/token", post(handle_api_request))
+        // Home Assistant login flow (only answered if the client proxies it)
+        .route("/auth/providers", get(handle_api_request))
+        .route("/auth/login_flow", post(handle_api_request))
+        .route(
+            "/auth/login_flow/{flow_id}",
+            post(handle_api_request).delete(handle_api_request),
+        )
+        .route("/manifest.json", get(handle_api_request))
+        .route("/frontend_latest/{*path}", get(handle_api_request))
+        .route("/frontend_es5/{*path}", get(handle_api_request))
+        .route("/static/{*path}", get(handle_api_request))
+        .route_layer(middleware::from_fn_with_state(
+            state.clone(),
+            record_request,
+        ))
+        // Tunnel endpoint (WebSocket)
+        .route("/tunnel", get(handle_tunnel_connection))
         // Health check at root
         .route("/health", get(health_check))
         .layer(TraceLayer::new_for_http())
@@ -48,15 +121,114 @@ pub fn create_router(state: Arc<ServerState>) -> Router {
 }
 
 async fn health_check(State(state): State<Arc<ServerState>>) -> impl IntoResponse {
-    let client_count = state.clients.len();
+    let connection_count = state.clients.len();
+    let client_count = state
+        .clients
+        .iter()
+        .map(|entry| entry.client_id.clone())
+        .collect::<HashSet<_>>()
+        .len();
     let status = if client_count > 0 { "ok" } else { "no_clients" };
 
     axum::Json(serde_json::json!({
         "status": status,
-        "clients": client_count
+        "clients": client_count,
+        "connections": connection_count
     }))
 }
 
+/// Takes the caller's `X-Request-Id` if it is usable, a new ID otherwise
+fn request_id(headers: &HeaderMap) -> String {
+    headers
+        .get(REQUEST_ID_HEADER)
+        .and_then(|value| value.to_str().ok())
+        .filter(|id| {
+            !id.is_empty()
+                && id.len() <= MAX_REQUEST_ID_LENGTH
+                && id.chars().all(|c| c.is_ascii_graphic())
+        })
+        .map(|id| id.to_string())
+        .unwrap_or_else(|| Uuid::new_v4().to_string())
+}
+
+/// Assigns the request its ID and records it with its outcome for the
+/// dashboard and the access log
+async fn record_request(
+    State(state): State<Arc<ServerState>>,
+    ConnectInfo(addr): ConnectInfo<SocketAddr>,
+    mut request: Request<Body>,
+    next: Next,
+) -> Response {
+    let request_id = request_id(request.headers());
+    let request_id_value = HeaderValue::from_str(&request_id).expect("request ID is ASCII");
+    request
+        .headers_mut()
+        .insert(REQUEST_ID_HEADER, request_id_value.clone());
+    request
+        .extensions_mut()
+        .insert(RequestId(request_id.clone()));
+
+    let method = request.method().to_string();
+    let path = request.uri().path().to_string();
+    let timestamp = Utc::now();
+    let started_at = Instant::now();
+    let source_ip = state.access_log.is_enabled().then(|| {
+        let config = state.config();
+        extract_client_ip(
+            request.headers(),
+            addr,
+            &config.proxy_mode,
+            &config.trusted_proxies,
+        )
+    });
+
+    // Joins the trace of the caller, if it sent one
+    let span = info_span!(
+        "request",
+        request_id = %request_id,
+        method = %method,
+        path = %path,
+        otel.kind = "server",
+        http.response.status_code = field::Empty,
+    );
+    set_parent_from_headers(&span, request.headers());
+
+    let mut response = next.run(request).instrument(span.clone()).await;
+    span.record("http.response.status_code", response.status().as_u16());
+    response
+        .headers_mut()
+        .insert(REQUEST_ID_HEADER, request_id_value);
+
+    let duration_ms = started_at.elapsed().as_millis() as u64;
+    let status = response.status().as_u16();
+    let tunneled = response.extensions().get::<Tunneled>();
+
+    state.access_log.log(&AccessLogEntry {
+        timestamp,
+        request_id: Some(request_id),
+        client_id: tunneled.map(|t| t.client_id.clone()),
+        source_ip,
+        method: method.clone(),
+        path: path.clone(),
+        status,
+        bytes: response.body().size_hint().exact().unwrap_or_default() as usize,
+        duration_ms,
+        tunnel_ms: tunneled.map(|t| t.tunnel_ms),
+        upstream_ms: tunneled.and_then(|t| t.upstream_ms),
+    });
+
+    state.request_stats.record(RequestRecord {
+        timestamp: now_as_secs(),
+        method,
+        path,
+        status,
+        latency_ms: duration_ms,
+        client_id: tunneled.map(|t| t.client_id.clone()),
+    });
+
+    response
+}
+
 async fn handle_tunnel_connection(
     ws: WebSocketUpgrade,
     State(state): State<Arc<ServerState>>,
@@ -64,10 +236,14 @@ async fn handle_tunnel_connection(
 ) -> impl IntoResponse {
     info!(addr = %addr, "New tunnel connection");
 
-    ws.on_upgrade(move |socket| handle_tunnel_socket(socket, state))
+    ws.on_upgrade(move |socket| handle_tunnel_socket(socket, state, addr))
 }
 
-async fn handle_tunnel_socket(socket: axum::extract::ws::WebSocket, state: Arc<ServerState>) {
+async fn handle_tunnel_socket(
+    socket: axum::extract::ws::WebSocket,
+    state: Arc<ServerState>,
+    remote_addr: SocketAddr,
+) {
     use axum::extract::ws::Message;
 
     let (mut ws_tx, mut ws_rx) = socket.split();
@@ -76,43 +252,58 @@ async fn handle_tunnel_socket(socket: axum::extract::ws::WebSocket, state: Arc<S
     let auth_timeout = Duration::from_secs(10);
     let auth_result = tokio::time::timeout(auth_timeout, ws_rx.next()).await;
 
-    let client_id = match auth_result {
+    let (client_id, compression, priority, weight, version) = match auth_result {
         Ok(Some(Ok(Message::Text(text)))) => {
             match serde_json::from_str::<TunnelMessage>(&text) {
                 Ok(TunnelMessage::Auth {
                     client_id,
                     timestamp,
                     signature,
+                    compression,
+                    priority,
+                    weight,
+                    version,
                 }) => {
-                    if verify_auth_signature(
+                    let rejection = if !verify_auth_signature(
                         &client_id,
                         timestamp,
                         &signature,
-                        &state.config.secret,
+                        &state.config().secret,
                     ) {
-                        info!(client_id = %client_id, "Client authenticated");
-
-                        // Send success response
-                        let response = TunnelMessage::AuthResponse {
-                            success: true,
-                            message: None,
-                        };
-                        let msg = serde_json::to_string(&response).unwrap();
-                        if ws_tx.send(Message::text(msg)).await.is_err() {
-                            return;
-                        }
-
-                        client_id
+                        Some("Invalid signature")
+                    } else if state.is_revoked(&client_id) {
+                        Some("Client revoked")
                     } else {
-                        warn!(client_id = %client_id, "Authentication failed");
+                        None
+                    };
+
+                    if let Some(reason) = rejection {
+                        warn!(client_id = %client_id, reason = reason, "Authentication failed");
                         let response = TunnelMessage::AuthResponse {
                             success: false,
-                            message: Some("Invalid signature".to_string()),
+                            message: Some(reason.to_string()),
+                            compression: None,
                         };
                         let msg = serde_json::to_string(&response).unwrap();
                         let _ = ws_tx.send(Message::text(msg)).await;
                         return;
                     }
+
+                    let compression = negotiate(&compression, &state.config().tunnel_compression);
+                    info!(client_id = %client_id, version = ?version, compression = ?compression, "Client authenticated");
+
+                    // Send success response
+                    let response = TunnelMessage::AuthResponse {
+                        success: true,
+                        message: None,
+                        compression,
+                    };
+                    let msg = serde_json::to_string(&response).unwrap();
+                    if ws_tx.send(Message::text(msg)).await.is_err() {
+                        return;
+                    }
+
+                    (client_id, compression, priority, weight, version)
                 }
                 _ => {
                     warn!("Invalid auth message");
@@ -128,23 +319,40 @@ async fn handle_tunnel_socket(socket: axum::extract::ws::WebSocket, state: Arc<S
 
     // Create channel for sending messages to this client
     let (tx, mut rx) = mpsc::channel::<TunnelMessage>(100);
+    let connection_id = Uuid::new_v4().to_string();
 
-    // Register client
+    // Notified to close the connection, once a client that announced it is
+    // going away has no requests in flight or by the admin API
+    let close = Arc::new(Notify::new());
+
+    // Clients older than the version announcement answer pings with an error
+    let answers_pings = version.is_some();
+
+    // Register connection
     state.clients.insert(
-        client_id.clone(),
+        connection_id.clone(),
         ClientConnection {
+            connection_id: connection_id.clone(),
             client_id: client_id.clone(),
             connected_at: now_as_secs(),
             last_ping: now_as_secs(),
             sender: tx,
+            compression,
+            priority,
+            weight,
+            rtt: None,
+            ping_sent_at: None,
+            remote_addr,
+            version,
+            close: close.clone(),
         },
     );
 
     // Notify waiters that a client connected
-    let client_count = state.clients.len();
-    let _ = state.client_connected_tx.send(client_count);
+    let connection_count = state.clients.len();
+    let _ = state.client_connected_tx.send(connection_count);
 
-    info!(client_id = %client_id, client_count = client_count, "Client connected");
+    info!(client_id = %client_id, connection_id = %connection_id, connection_count = connection_count, "Client connected");
 
     // Spawn task to forward outbound messages
     let outbound_client_id = client_id.clone();
@@ -164,12 +372,64 @@ async fn handle_tunnel_socket(socket: axum::extract::ws::WebSocket, state: Arc<S
         debug!(client_id = %outbound_client_id, "Outbound task ended");
     });
 
+    // Ping the connection regularly to measure its round trip time, while the
+    // lowest latency strategy is selected
+    let probe_state = state.clone();
+    let probe_connection_id = connection_id.clone();
+    let probe_task = answers_pings.then(|| {
+        tokio::spawn(async move {
+            let mut interval = tokio::time::interval(RTT_PROBE_INTERVAL);
+            loop {
+                interval.tick().await;
+                if probe_state.config().load_balancing != LoadBalancing::LowestLatency {
+                    continue;
+                }
+                let sender = probe_state
+                    .clients
+                    .get_mut(&probe_connection_id)
+                    .map(|mut client| {
+                        client.ping_sent_at = Some(Instant::now());
+                        client.sender.clone()
+                    });
+                let ping = TunnelMessage::Ping {
+                    timestamp: now_as_secs(),
+                };
+                match sender {
+                    Some(sender) if sender.send(ping).await.is_ok() => {}
+                    _ => break,
+                }
+            }
+        })
+    });
+
     // Process incoming messages
-    while let Some(msg) = ws_rx.next().await {
+    loop {
+        let msg = tokio::select! {
+            msg = ws_rx.next() => msg,
+            _ = close.notified() => {
+                info!(client_id = %client_id, connection_id = %connection_id, "Closing connection");
+                break;
+            }
+        };
+        let Some(msg) = msg else {
+            break;
+        };
+
         match msg {
             Ok(Message::Text(text)) => match serde_json::from_str::<TunnelMessage>(&text) {
+                Ok(TunnelMessage::GoingAway { grace_period }) => {
+                    info!(client_id = %client_id, connection_id = %connection_id, grace_period = grace_period, "Client is going away, draining");
+                    // Stop routing new requests to this connection
+                    state.clients.remove(&connection_id);
+                    tokio::spawn(wait_for_drain(
+                        state.clone(),
+                        connection_id.clone(),
+                        Duration::from_secs(grace_period),
+                        close.clone(),
+                    ));
+                }
                 Ok(tunnel_msg) => {
-                    handle_client_message(&state, &client_id, tunnel_msg).await;
+                    handle_client_message(&state, &connection_id, tunnel_msg).await;
                 }
                 Err(e) => {
                     warn!("Failed to parse message: {}", e);
@@ -188,56 +448,142 @@ async fn handle_tunnel_socket(socket: axum::extract::ws::WebSocket, state: Arc<S
     }
 
     // Cleanup
-    state.clients.remove(&client_id);
+    state.clients.remove(&connection_id);
+    if let Some(probe_task) = probe_task {
+        probe_task.abort();
+    }
     outbound_task.abort();
 
-    info!(client_id = %client_id, "Client removed");
+    info!(client_id = %client_id, connection_id = %connection_id, "Client removed");
+}
+
+/// Waits until no requests sent over the connection are in flight anymore,
+/// bounded by the grace period
+async fn wait_for_drain(
+    state: Arc<ServerState>,
+    connection_id: String,
+    grace_period: Duration,
+    close: Arc<Notify>,
+) {
+    let result = tokio::time::timeout(grace_period, async {
+        while state
+            .pending_requests
+            .iter()
+            .any(|pending| pending.connection_id == connection_id)
+        {
+            tokio::time::sleep(DRAIN_POLL_INTERVAL).await;
+        }
+    })
+    .await;
+
+    if result.is_err() {
+        warn!(connection_id = %connection_id, "Grace period expired with requests still in flight");
+    }
+    close.notify_one();
+}
+
+/// Tells all connected clients that the server is shutting down so they can
+/// connect elsewhere while requests in flight are completed
+pub async fn announce_shutdown(state: &Arc<ServerState>) {
+    let clients: Vec<ClientConnection> = state
+        .clients
+        .iter()
+        .map(|entry| entry.value().clone())
+        .collect();
+
+    for client in clients {
+        let msg = TunnelMessage::GoingAway {
+            grace_period: state.config().shutdown_grace_period,
+        };
+        if client.sender.send(msg).await.is_err() {
+            debug!(client_id = %client.client_id, "Failed to notify client about shutdown");
+        }
+    }
 }
 
-async fn handle_client_message(state: &Arc<ServerState>, client_id: &str, msg: TunnelMessage) {
+async fn handle_client_message(state: &Arc<ServerState>, connection_id: &str, msg: TunnelMessage) {
     match msg {
         TunnelMessage::HttpResponse { ref request_id, .. } => {
             // Find pending request and send response
-            if let Some((_, sender)) = state.pending_requests.remove(request_id) {
-                let _ = sender.send(msg);
+            if let Some((_, pending)) = state.pending_requests.remove(request_id) {
+                let _ = pending.sender.send(msg);
             } else {
                 warn!(request_id = %request_id, "No pending request found");
             }
         }
         TunnelMessage::Error { ref request_id, .. } => {
             if let Some(request_id) = &request_id
-                && let Some((_, sender)) = state.pending_requests.remove(request_id)
+                && let Some((_, pending)) = state.pending_requests.remove(request_id)
             {
-                let _ = sender.send(msg);
+                let _ = pending.sender.send(msg);
             }
         }
         TunnelMessage::Ping { timestamp } => {
-            if let Some(mut client) = state.clients.get_mut(client_id) {
+            // Don't hold the map entry across the send
+            let sender = state.clients.get_mut(connection_id).map(|mut client| {
                 client.last_ping = now_as_secs();
-
+                client.sender.clone()
+            });
+            if let Some(sender) = sender {
                 let response = TunnelMessage::Pong { timestamp };
-                if let Err(e) = client.sender.send(response).await {
+                if let Err(e) = sender.send(response).await {
                     error!("Failed to send message: {}", e);
                 }
             }
-            debug!(client_id = %client_id, latency_s = %(now_as_secs() - timestamp), "Ping received");
+            debug!(connection_id = %connection_id, latency_s = %now_as_secs().saturating_sub(timestamp), "Ping received");
+        }
+        TunnelMessage::Pong { .. } => {
+            if let Some(mut client) = state.clients.get_mut(connection_id)
+                && let Some(sent_at) = client.ping_sent_at.take()
+            {
+                let rtt = sent_at.elapsed();
+                client.rtt = Some(rtt);
+                debug!(connection_id = %connection_id, rtt_ms = rtt.as_millis(), "Pong received");
+            }
         }
         _ => {
-            warn!(client_id = %client_id, "Unexpected message type");
+            warn!(connection_id = %connection_id, "Unexpected message type");
         }
     }
 }
 
-/// Find a client not in the exclude set
+/// Find a connection not in the exclude set, picked by the configured load
+/// balancing strategy
 fn find_client_excluding(
     state: &Arc<ServerState>,
     exclude: &HashSet<String>,
 ) -> Option<ClientConnection> {
-    state
+    let mut connections: Vec<ClientConnection> = state
         .clients
         .iter()
-        .find(|entry| !exclude.contains(entry.key()))
+        .filter(|entry| !exclude.contains(entry.key()) && !entry.sender.is_closed())
         .map(|entry| entry.value().clone())
+        .collect();
+    if connections.is_empty() {
+        return None;
+    }
+    connections.sort_by(|a, b| a.connection_id.cmp(&b.connection_id));
+
+    let mut outstanding: HashMap<String, usize> = HashMap::new();
+    for pending in state.pending_requests.iter() {
+        *outstanding.entry(pending.client_id.clone()).or_default() += 1;
+    }
+    let candidates: Vec<Candidate> = connections
+        .iter()
+        .map(|connection| Candidate {
+            outstanding: outstanding
+                .get(&connection.client_id)
+                .copied()
+                .unwrap_or_default(),
+            rtt: connection.rtt,
+            priority: connection.priority,
+            weight: connection.weight,
+        })
+        .collect();
+
+    let counter = state.next_connection.fetch_add(1, Ordering::Relaxed);
+    let index = state.config().load_balancing.select(&candidates, counter)?;
+    Some(connections.swap_remove(index))
 }
 
 /// Get an available client, waiting if necessary
@@ -286,19 +632,37 @@ async fn handle_api_request(
     ConnectInfo(addr): ConnectInfo<SocketAddr>,
     request: Request<Body>,
 ) -> Response {
+    let config = state.config();
+    let correlation_id = request
+        .extensions()
+        .get::<RequestId>()
+        .map(|id| id.0.clone());
     let method = request.method().to_string();
     let path = request.uri().path().to_string();
     let source_ip = extract_client_ip(
         request.headers(),
         addr,
-        &state.config.proxy_mode,
-        &state.config.trusted_proxies,
+        &config.proxy_mode,
+        &config.trusted_proxies,
     );
 
     debug!(method = %method, path = %path, source_ip = %source_ip, direct_ip = %addr.ip(), "API request received");
 
+    if let Err(e) = state.ip_filter.check(&path, &source_ip) {
+        warn!(path = %path, source_ip = %source_ip, error = %e, "Request denied by IP filter");
+        return e.into();
+    }
+
+    if let Err(e) = state
+        .rate_limiter
+        .check(&path, RateLimitKey::Ip, &source_ip)
+    {
+        warn!(path = %path, source_ip = %source_ip, "Request rate limited");
+        return e.into();
+    }
+
     // Extract request details once (before retry loop)
-    let headers: Vec<(String, String)> = request
+    let mut headers: Vec<(String, String)> = request
         .headers()
         .iter()
         .filter_map(|(name, value)| {
@@ -313,7 +677,7 @@ async fn handle_api_request(
         .collect();
 
     let query = request.uri().query().map(|s| s.to_string());
-    let body = match axum::body::to_bytes(request.into_body(), 10 * 1024 * 1024).await {
+    let body = match axum::body::to_bytes(request.into_body(), MAX_BODY_SIZE).await {
         Ok(bytes) => {
             if bytes.is_empty() {
                 None
@@ -324,46 +688,115 @@ async fn handle_api_request(
         Err(_) => None,
     };
 
+    if config.alexa_validation
+        && path == ALEXA_PATH
+        && let Err(e) = validate_alexa_request(body.as_deref(), config.alexa_max_body_size)
+    {
+        warn!(path = %path, source_ip = %source_ip, error = %e, "Rejected invalid Alexa directive");
+        return e.into();
+    }
+
+    if config.google_validation
+        && path == GOOGLE_PATH
+        && let Err(e) =
+            validate_google_request(&headers, body.as_deref(), config.google_max_body_size)
+    {
+        warn!(path = %path, source_ip = %source_ip, error = %e, "Rejected invalid Google Assistant request");
+        return e.into();
+    }
+
+    // Answer fresh responses from the cache, revalidate stale ones through the tunnel
+    let cache_key = state
+        .response_cache
+        .is_cacheable_request(&method, &path, &headers)
+        .then(|| ResponseCache::key(&path, query.as_deref()));
+    let cached = cache_key
+        .as_deref()
+        .and_then(|key| state.response_cache.get(key, &headers));
+    if let Some(cached) = &cached {
+        if cached.is_fresh() {
+            debug!(path = %path, "Serving response from cache");
+            return build_response(
+                cached.status,
+                cached.headers.clone(),
+                Some(cached.body.clone()),
+            );
+        }
+        headers.extend(cached.validators());
+    }
+
     // Get timeouts from config
-    let wait_timeout = Duration::from_secs(state.config.client_timeout);
-    let request_timeout = Duration::from_secs(state.config.request_timeout);
+    let wait_timeout = Duration::from_secs(config.client_timeout);
+    let request_timeout = Duration::from_secs(config.request_timeout);
 
-    // Track clients we've already tried (for retry logic)
-    let mut tried_clients: HashSet<String> = HashSet::new();
+    // Track connections we've already tried (for retry logic)
+    let mut tried_connections: HashSet<String> = HashSet::new();
+    // Set once a client was skipped for its rate limit
+    let mut rate_limited: Option<ProxyError> = None;
 
     // Retry loop: attempt to send to available clients
     for attempt in 1..=MAX_REQUEST_RETRIES {
-        // Get an available client (waiting if necessary on first attempt)
-        let client = match get_available_client(&state, wait_timeout, &tried_clients).await {
-            Some(c) => c,
-            None => {
+        // Get an available client that is not rate limited (waiting if
+        // necessary until one connects, unless one was skipped already)
+        let client = loop {
+            let client = match rate_limited {
+                Some(_) => find_client_excluding(&state, &tried_connections),
+                None => get_available_client(&state, wait_timeout, &tried_connections).await,
+            };
+            let Some(client) = client else {
+                if let Some(e) = rate_limited {
+                    warn!(path = %path, "Request rate limited for every client");
+                    return e.into();
+                }
                 return (
                     StatusCode::SERVICE_UNAVAILABLE,
                     "No connected clients (timeout waiting for client)",
                 )
                     .into_response();
+            };
+            tried_connections.insert(client.connection_id.clone());
+
+            match state
+                .rate_limiter
+                .check(&path, RateLimitKey::Client, &client.client_id)
+            {
+                Ok(()) => break client,
+                Err(e) => {
+                    debug!(path = %path, client_id = %client.client_id, "Client rate limited, trying another one");
+                    rate_limited = Some(e);
+                }
             }
         };
-
         let client_id = client.client_id.clone();
-        tried_clients.insert(client_id.clone());
 
         // Create new request_id for each attempt
         let request_id = Uuid::new_v4().to_string();
         let (response_tx, response_rx) = oneshot::channel();
-        state
-            .pending_requests
-            .insert(request_id.clone(), response_tx);
+        state.pending_requests.insert(
+            request_id.clone(),
+            PendingRequest {
+                client_id: client_id.clone(),
+                connection_id: client.connection_id.clone(),
+                method: method.clone(),
+                path: path.clone(),
+                started_at: Instant::now(),
+                sender: response_tx,
+            },
+        );
 
         // Build the tunnel request (clone data for this attempt)
+        let (request_body, body_compression) = compress_body(client.compression, body.clone());
         let tunnel_request = TunnelMessage::HttpRequest {
             request_id: request_id.clone(),
             method: method.clone(),
             path: path.clone(),
             query: query.clone(),
             headers: headers.clone(),
-            body: body.clone(),
+            body: request_body,
+            body_compression,
             source_ip: Some(source_ip.clone()),
+            correlation_id: correlation_id.clone(),
+            trace_context: inject_context(&Span::current()),
         };
 
         // Try to send to client
@@ -371,49 +804,73 @@ async fn handle_api_request(
             state.pending_requests.remove(&request_id);
             warn!(
                 client_id = %client_id,
+                connection_id = %client.connection_id,
                 attempt = attempt,
                 max_attempts = MAX_REQUEST_RETRIES,
-                "Failed to send to client, retrying with another client..."
+                "Failed to send to client, retrying with another connection..."
             );
             continue; // Try next client
         }
 
         // Wait for response with timeout (no retries for response-phase failures)
-        return match tokio::time::timeout(request_timeout, response_rx).await {
+        let sent_at = Instant::now();
+        let mut upstream_ms = None;
+        let mut response = match tokio::time::timeout(request_timeout, response_rx).await {
             Ok(Ok(TunnelMessage::HttpResponse {
                 status,
-                headers: resp_headers,
+                headers: mut resp_headers,
                 body: resp_body,
+                body_compression,
+                upstream_ms: client_upstream_ms,
                 ..
             })) => {
-                let status_code =
-                    StatusCode::from_u16(status).unwrap_or(StatusCode::INTERNAL_SERVER_ERROR);
-                let mut header_map = HeaderMap::new();
-
-                for (name, value) in resp_headers {
-                    // Skip hop-by-hop headers that shouldn't be forwarded through proxies
-                    let name_lower = name.to_lowercase();
-                    if matches!(
-                        name_lower.as_str(),
-                        "transfer-encoding"
-                            | "connection"
-                            | "keep-alive"
-                            | "te"
-                            | "trailers"
-                            | "upgrade"
-                    ) {
-                        debug!(header = %name, "Skipping hop-by-hop header");
-                        continue;
+                upstream_ms = client_upstream_ms;
+                // Hand the tunnel compressed body straight to callers accepting
+                // its encoding (cached responses are always stored decompressed)
+                let resp_body = match body_compression {
+                    Some(compression)
+                        if cache_key.is_none()
+                            && accepts_encoding(&headers, compression.encoding()) =>
+                    {
+                        resp_headers
+                            .retain(|(name, _)| !name.eq_ignore_ascii_case("content-length"));
+                        resp_headers.push((
+                            "content-encoding".to_string(),
+                            compression.encoding().to_string(),
+                        ));
+                        add_vary(&mut resp_headers, "accept-encoding");
+                        resp_body
                     }
-                    if let (Ok(header_name), Ok(header_value)) = (
-                        name.parse::<axum::http::header::HeaderName>(),
-                        value.parse::<axum::http::header::HeaderValue>(),
-                    ) {
-                        header_map.insert(header_name, header_value);
+                    _ => match decompress_body(body_compression, resp_body) {
+                        Ok(body) => body,
+                        Err(e) => {
+                            error!(client_id = %client_id, error = %e, "Failed to decompress response body");
+                            return (StatusCode::BAD_GATEWAY, "Invalid response body")
+                                .into_response();
+                        }
+                    },
+                };
+
+                if let Some(key) = cache_key {
+                    // Answer from the entry the validators came from, even if it
+                    // was evicted meanwhile, the caller sent no conditional headers
+                    if status == 304
+                        && let Some(cached) = cached
+                    {
+                        debug!(path = %path, "Cached response revalidated");
+                        let entry = state.response_cache.revalidated(key, cached, &resp_headers);
+                        return build_response(entry.status, entry.headers, Some(entry.body));
                     }
+                    state.response_cache.store(
+                        key,
+                        &headers,
+                        status,
+                        &resp_headers,
+                        resp_body.as_deref().unwrap_or_default(),
+                    );
                 }
 
-                (status_code, header_map, resp_body.unwrap_or_default()).into_response()
+                build_response(status, resp_headers, resp_body)
             }
             Ok(Ok(TunnelMessage::Error { message, .. })) => {
                 // Client returned an error - don't retry, this is intentional
@@ -434,6 +891,12 @@ async fn handle_api_request(
                 (StatusCode::GATEWAY_TIMEOUT, "Request timeout").into_response()
             }
         };
+        response.extensions_mut().insert(Tunneled {
+            client_id,
+            tunnel_ms: sent_at.elapsed().as_millis() as u64,
+            upstream_ms,
+        });
+        return response;
     }
 
     // All retries exhausted (only reached if all send attempts failed)
@@ -447,3 +910,142 @@ async fn handle_api_request(
     )
         .into_response()
 }
+
+/// Returns true if the caller's `Accept-Encoding` header allows the encoding
+fn accepts_encoding(headers: &[(String, String)], encoding: &str) -> bool {
+    headers
+        .iter()
+        .filter(|(name, _)| name.eq_ignore_ascii_case("accept-encoding"))
+        .flat_map(|(_, value)| value.split(','))
+        .any(|item| {
+            let mut parts = item.split(';').map(str::trim);
+            let token = parts.next().unwrap_or_default();
+            let rejected = parts.any(|p| {
+                p.strip_prefix("q=")
+                    .and_then(|q| q.parse::<f32>().ok())
+                    .is_some_and(|q| q == 0.0)
+            });
+            token.eq_ignore_ascii_case(encoding) && !rejected
+        })
+}
+
+/// Adds a request header name to the `Vary` response header, merged into a
+/// single header with the names listed by upstream
+fn add_vary(headers: &mut Vec<(String, String)>, name: &str) {
+    let mut names: Vec<String> = headers
+        .iter()
+        .filter(|(n, _)| n.eq_ignore_ascii_case("vary"))
+        .flat_map(|(_, value)| value.split(','))
+        .map(|item| item.trim().to_string())
+        .filter(|item| !item.is_empty())
+        .collect();
+    headers.retain(|(n, _)| !n.eq_ignore_ascii_case("vary"));
+
+    if !names
+        .iter()
+        .any(|item| item == "*" || item.eq_ignore_ascii_case(name))
+    {
+        names.push(name.to_string());
+    }
+    headers.push(("vary".to_string(), names.join(", ")));
+}
+
+/// Converts a tunneled response into the public response
+fn build_response(status: u16, headers: Vec<(String, String)>, body: Option<Vec<u8>>) -> Response {
+    let status_code = StatusCode::from_u16(status).unwrap_or(StatusCode::INTERNAL_SERVER_ERROR);
+    let mut header_map = HeaderMap::new();
+
+    for (name, value) in headers {
+        // Skip hop-by-hop headers that shouldn't be forwarded through proxies
+        let name_lower = name.to_lowercase();
+        if matches!(
+            name_lower.as_str(),
+            "transfer-encoding" | "connection" | "keep-alive" | "te" | "trailers" | "upgrade"
+        ) {
+            debug!(header = %name, "Skipping hop-by-hop header");
+            continue;
+        }
+        if let (Ok(header_name), Ok(header_value)) = (
+            name.parse::<axum::http::header::HeaderName>(),
+            value.parse::<axum::http::header::HeaderValue>(),
+        ) {
+            header_map.insert(header_name, header_value);
+        }
+    }
+
+    (status_code, header_map, body.unwrap_or_default()).into_response()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn headers(values: &[(&str, &str)]) -> Vec<(String, String)> {
+        values
+            .iter()
+            .map(|(n, v)| (n.to_string(), v.to_string()))
+            .collect()
+    }
+
+    #[test]
+    fn test_add_vary_merges_upstream_vary() {
+        let mut response = headers(&[("Vary", "Origin"), ("vary", "Accept-Language")]);
+        add_vary(&mut response, "accept-encoding");
+        assert_eq!(
+            response,
+            headers(&[("vary", "Origin, Accept-Language, accept-encoding")])
+        );
+
+        let mut response = headers(&[("Vary", "Accept-Encoding")]);
+        add_vary(&mut response, "accept-encoding");
+        assert_eq!(response, headers(&[("vary", "Accept-Encoding")]));
+
+        let mut response = vec![];
+        add_vary(&mut response, "accept-encoding");
+        assert_eq!(response, headers(&[("vary", "accept-encoding")]));
+    }
+
+    fn request_id_from(value: HeaderValue) -> String {
+        let mut headers = HeaderMap::new();
+        headers.insert(REQUEST_ID_HEADER, value);
+        request_id(&headers)
+    }
+
+    fn is_generated(id: &str) -> bool {
+        Uuid::parse_str(id).is_ok()
+    }
+
+    #[test]
+    fn test_request_id_from_caller() {
+        assert_eq!(
+            request_id_from(HeaderValue::from_static("req-42_abc.def")),
+            "req-42_abc.def"
+        );
+        let longest = "a".repeat(MAX_REQUEST_ID_LENGTH);
+        assert_eq!(
+            request_id_from(HeaderValue::from_str(&longest).unwrap()),
+            longest
+        );
+    }
+
+    #[test]
+    fn test_request_id_replaces_invalid() {
+        assert!(is_generated(&request_id(&HeaderMap::new())));
+        assert!(is_generated(&request_id_from(HeaderValue::from_static(""))));
+
+        let too_long = "a".repeat(MAX_REQUEST_ID_LENGTH + 1);
+        assert!(is_generated(&request_id_from(
+            HeaderValue::from_str(&too_long).unwrap()
+        )));
+
+        assert!(is_generated(&request_id_from(HeaderValue::from_static(
+            "two words"
+        ))));
+        assert!(is_generated(&request_id_from(HeaderValue::from_static(
+            "tab\tid"
+        ))));
+        assert!(is_generated(&request_id_from(
+            HeaderValue::from_bytes("caf\u{e9}".as_bytes()).unwrap()
+        )));
+    }
+}
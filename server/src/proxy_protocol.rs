@@ -0,0 +1,311 @@
+use crate::config::Config;
+use axum::serve::Listener;
+use common::error::ProxyError;
+use std::io;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::io::{AsyncRead, AsyncReadExt};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::sync::{mpsc, watch};
+use tracing::{debug, error, warn};
+
+/// Maximum time a connection gets to send its PROXY protocol header
+const HEADER_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Binary signature every PROXY protocol v2 header starts with
+const V2_SIGNATURE: [u8; 12] = *b"\r\n\r\n\0\r\nQUIT\n";
+
+/// Maximum length of a PROXY protocol v1 header line (including CRLF)
+const V1_MAX_LENGTH: usize = 107;
+
+/// A listener that expects every connection to start with a PROXY protocol
+/// (v1 or v2) header and reports the address from that header as the remote
+/// address of the connection.
+///
+/// Headers are read on a separate task per connection so a slow or malicious
+/// peer can't stall accepting other connections. The trusted proxies are
+/// taken from the current configuration for every connection.
+pub struct ProxyProtocolListener {
+    local_addr: SocketAddr,
+    incoming: mpsc::Receiver<(TcpStream, SocketAddr)>,
+}
+
+impl ProxyProtocolListener {
+    pub fn new(listener: TcpListener, config: watch::Receiver<Arc<Config>>) -> io::Result<Self> {
+        let local_addr = listener.local_addr()?;
+        let (tx, incoming) = mpsc::channel(128);
+
+        tokio::spawn(async move {
+            loop {
+                let (mut stream, peer_addr) = match listener.accept().await {
+                    Ok(conn) => conn,
+                    Err(e) => {
+                        error!(error = %e, "Failed to accept connection");
+                        tokio::time::sleep(Duration::from_secs(1)).await;
+                        continue;
+                    }
+                };
+
+                let tx = tx.clone();
+                let trusted = {
+                    let trusted_proxies = &config.borrow().trusted_proxies;
+                    trusted_proxies.is_empty() || trusted_proxies.contains(&peer_addr.ip())
+                };
+                tokio::spawn(async move {
+                    let header =
+                        tokio::time::timeout(HEADER_TIMEOUT, read_header(&mut stream)).await;
+                    let addr = match header {
+                        Ok(Ok(Some(addr))) if trusted => addr,
+                        Ok(Ok(Some(addr))) => {
+                            debug!(
+                                peer_addr = %peer_addr,
+                                header_addr = %addr,
+                                "PROXY header from untrusted peer, using direct IP"
+                            );
+                            peer_addr
+                        }
+                        Ok(Ok(None)) => peer_addr,
+                        Ok(Err(e)) => {
+                            warn!(peer_addr = %peer_addr, error = %e, "Invalid PROXY protocol header");
+                            return;
+                        }
+                        Err(_) => {
+                            warn!(peer_addr = %peer_addr, "Timeout waiting for PROXY protocol header");
+                            return;
+                        }
+                    };
+
+                    let _ = tx.send((stream, addr)).await;
+                });
+            }
+        });
+
+        Ok(Self {
+            local_addr,
+            incoming,
+        })
+    }
+}
+
+impl Listener for ProxyProtocolListener {
+    type Io = TcpStream;
+    type Addr = SocketAddr;
+
+    async fn accept(&mut self) -> (Self::Io, Self::Addr) {
+        match self.incoming.recv().await {
+            Some(conn) => conn,
+            // The accept task only ends together with the runtime
+            None => std::future::pending().await,
+        }
+    }
+
+    fn local_addr(&self) -> io::Result<Self::Addr> {
+        Ok(self.local_addr)
+    }
+}
+
+/// Reads a PROXY protocol header from the stream, consuming exactly the header
+/// bytes. Returns `None` if the header doesn't carry an address (v1 `UNKNOWN`
+/// or v2 `LOCAL`), in which case the direct connection address should be used.
+async fn read_header<R: AsyncRead + Unpin>(
+    stream: &mut R,
+) -> Result<Option<SocketAddr>, ProxyError> {
+    // Both versions are at least 12 bytes long ("PROXY UNKNOWN\r\n" is 15)
+    let mut prefix = [0u8; 12];
+    stream.read_exact(&mut prefix).await?;
+
+    if prefix == V2_SIGNATURE {
+        let mut header = [0u8; 4];
+        stream.read_exact(&mut header).await?;
+        let length = u16::from_be_bytes([header[2], header[3]]) as usize;
+        let mut payload = vec![0u8; length];
+        stream.read_exact(&mut payload).await?;
+
+        parse_v2(header[0], header[1], &payload)
+    } else if prefix.starts_with(b"PROXY ") {
+        let mut line = prefix.to_vec();
+        while !line.ends_with(b"\r\n") {
+            if line.len() >= V1_MAX_LENGTH {
+                return Err(ProxyError::InvalidRequest(
+                    "PROXY v1 header too long".to_string(),
+                ));
+            }
+            line.push(stream.read_u8().await?);
+        }
+
+        let line = std::str::from_utf8(&line)
+            .map_err(|_| ProxyError::InvalidRequest("PROXY v1 header is not ASCII".to_string()))?;
+        parse_v1(line)
+    } else {
+        Err(ProxyError::InvalidRequest(
+            "Missing PROXY protocol header".to_string(),
+        ))
+    }
+}
+
+/// Parses a PROXY protocol v1 header line.
+/// Format: "PROXY TCP4 192.0.2.1 198.51.100.1 56324 443\r\n"
+fn parse_v1(line: &str) -> Result<Option<SocketAddr>, ProxyError> {
+    let invalid = || ProxyError::InvalidRequest(format!("Invalid PROXY v1 header: {:?}", line));
+
+    let mut parts = line.trim_end_matches("\r\n").split(' ');
+    if parts.next() != Some("PROXY") {
+        return Err(invalid());
+    }
+
+    match parts.next() {
+        Some("UNKNOWN") => Ok(None),
+        Some(protocol @ ("TCP4" | "TCP6")) => {
+            let source_ip: IpAddr = parts
+                .next()
+                .and_then(|ip| ip.parse().ok())
+                .ok_or_else(invalid)?;
+            let _destination_ip: IpAddr = parts
+                .next()
+                .and_then(|ip| ip.parse().ok())
+                .ok_or_else(invalid)?;
+            let source_port: u16 = parts
+                .next()
+                .and_then(|p| p.parse().ok())
+                .ok_or_else(invalid)?;
+            let _destination_port: u16 = parts
+                .next()
+                .and_then(|p| p.parse().ok())
+                .ok_or_else(invalid)?;
+
+            if parts.next().is_some() || (protocol == "TCP4") != source_ip.is_ipv4() {
+                return Err(invalid());
+            }
+
+            Ok(Some(SocketAddr::new(source_ip, source_port)))
+        }
+        _ => Err(invalid()),
+    }
+}
+
+/// Parses the PROXY protocol v2 header fields following the signature.
+fn parse_v2(
+    version_command: u8,
+    family: u8,
+    payload: &[u8],
+) -> Result<Option<SocketAddr>, ProxyError> {
+    if version_command >> 4 != 2 {
+        return Err(ProxyError::InvalidRequest(
+            "Unsupported PROXY protocol version".to_string(),
+        ));
+    }
+
+    match version_command & 0x0F {
+        // LOCAL: health checks from the proxy itself
+        0x0 => return Ok(None),
+        // PROXY
+        0x1 => {}
+        _ => {
+            return Err(ProxyError::InvalidRequest(
+                "Unsupported PROXY v2 command".to_string(),
+            ));
+        }
+    }
+
+    let too_short = || ProxyError::InvalidRequest("PROXY v2 address block too short".to_string());
+
+    match family >> 4 {
+        // AF_INET: src_addr(4) dst_addr(4) src_port(2) dst_port(2)
+        0x1 => {
+            let block = payload.get(..12).ok_or_else(too_short)?;
+            let ip = Ipv4Addr::new(block[0], block[1], block[2], block[3]);
+            let port = u16::from_be_bytes([block[8], block[9]]);
+            Ok(Some(SocketAddr::new(IpAddr::V4(ip), port)))
+        }
+        // AF_INET6: src_addr(16) dst_addr(16) src_port(2) dst_port(2)
+        0x2 => {
+            let block = payload.get(..36).ok_or_else(too_short)?;
+            let mut octets = [0u8; 16];
+            octets.copy_from_slice(&block[..16]);
+            let port = u16::from_be_bytes([block[32], block[33]]);
+            Ok(Some(SocketAddr::new(
+                IpAddr::V6(Ipv6Addr::from(octets)),
+                port,
+            )))
+        }
+        // AF_UNSPEC / AF_UNIX: no usable client address
+        _ => Ok(None),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_v1_tcp4() {
+        assert_eq!(
+            parse_v1("PROXY TCP4 192.0.2.1 198.51.100.1 56324 443\r\n").unwrap(),
+            Some("192.0.2.1:56324".parse().unwrap())
+        );
+    }
+
+    #[test]
+    fn test_parse_v1_tcp6() {
+        assert_eq!(
+            parse_v1("PROXY TCP6 2001:db8::1 2001:db8::2 56324 443\r\n").unwrap(),
+            Some("[2001:db8::1]:56324".parse().unwrap())
+        );
+    }
+
+    #[test]
+    fn test_parse_v1_unknown() {
+        assert_eq!(parse_v1("PROXY UNKNOWN\r\n").unwrap(), None);
+    }
+
+    #[test]
+    fn test_parse_v1_family_mismatch() {
+        assert!(parse_v1("PROXY TCP4 2001:db8::1 2001:db8::2 56324 443\r\n").is_err());
+    }
+
+    #[test]
+    fn test_parse_v2_ipv4() {
+        let payload = [192, 0, 2, 1, 198, 51, 100, 1, 0xDC, 0x04, 0x01, 0xBB];
+        assert_eq!(
+            parse_v2(0x21, 0x11, &payload).unwrap(),
+            Some("192.0.2.1:56324".parse().unwrap())
+        );
+    }
+
+    #[test]
+    fn test_parse_v2_local() {
+        assert_eq!(parse_v2(0x20, 0x00, &[]).unwrap(), None);
+    }
+
+    #[tokio::test]
+    async fn test_read_header_consumes_only_header() {
+        let mut data: &[u8] = b"PROXY TCP4 192.0.2.1 198.51.100.1 56324 443\r\nGET / HTTP/1.1\r\n";
+        assert_eq!(
+            read_header(&mut data).await.unwrap(),
+            Some("192.0.2.1:56324".parse().unwrap())
+        );
+        assert_eq!(data, b"GET / HTTP/1.1\r\n");
+    }
+
+    #[tokio::test]
+    async fn test_read_header_v2() {
+        let mut data = V2_SIGNATURE.to_vec();
+        data.extend_from_slice(&[0x21, 0x11, 0x00, 0x0C]);
+        data.extend_from_slice(&[192, 0, 2, 1, 198, 51, 100, 1, 0xDC, 0x04, 0x01, 0xBB]);
+        data.extend_from_slice(b"GET");
+
+        let mut reader: &[u8] = &data;
+        assert_eq!(
+            read_header(&mut reader).await.unwrap(),
+            Some("192.0.2.1:56324".parse().unwrap())
+        );
+        assert_eq!(reader, b"GET");
+    }
+
+    #[tokio::test]
+    async fn test_read_header_missing() {
+        let mut data: &[u8] = b"GET / HTTP/1.1\r\nHost: x\r\n";
+        assert!(read_header(&mut data).await.is_err());
+    }
+}
@@ -0,0 +1,215 @@
+use common::error::ProxyError;
+use dashmap::DashMap;
+use serde::Deserialize;
+use std::time::{Duration, Instant};
+
+/// Interval at which idle rate limit buckets are dropped
+pub const CLEANUP_INTERVAL: Duration = Duration::from_secs(60);
+
+/// What a rate limit bucket is keyed on
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum RateLimitKey {
+    /// Real client IP as returned by `extract_client_ip` (default)
+    #[default]
+    Ip,
+    /// Tunnel client the request is forwarded to
+    Client,
+}
+
+/// A single rate limit rule as configured under `[[rate_limits]]`
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+pub struct RateLimitRule {
+    /// Request path the rule applies to, or `*` for all API routes
+    pub route: String,
+    /// Sustained number of requests allowed per minute
+    pub requests_per_minute: u32,
+    /// Number of requests that can be made in a burst (defaults to `requests_per_minute`)
+    pub burst: Option<u32>,
+    #[serde(default)]
+    pub key: RateLimitKey,
+}
+
+impl RateLimitRule {
+    fn matches(&self, path: &str, key: RateLimitKey) -> bool {
+        self.key == key && (self.route == "*" || self.route == path)
+    }
+
+    fn capacity(&self) -> f64 {
+        self.burst.unwrap_or(self.requests_per_minute).max(1) as f64
+    }
+
+    fn refill_per_sec(&self) -> f64 {
+        self.requests_per_minute as f64 / 60.0
+    }
+}
+
+#[derive(Debug)]
+struct TokenBucket {
+    tokens: f64,
+    updated_at: Instant,
+}
+
+/// Token bucket rate limiter for the public API routes
+pub struct RateLimiter {
+    rules: Vec<RateLimitRule>,
+    /// Buckets indexed by (rule index, key value)
+    buckets: DashMap<(usize, String), TokenBucket>,
+}
+
+impl RateLimiter {
+    pub fn new(rules: Vec<RateLimitRule>) -> Self {
+        Self {
+            rules,
+            buckets: DashMap::new(),
+        }
+    }
+
+    /// Takes a token from every bucket matching the path and key. Returns
+    /// `ProxyError::RateLimited` with the time until the next token is
+    /// available if any of them is empty, without taking any token.
+    pub fn check(&self, path: &str, key: RateLimitKey, value: &str) -> Result<(), ProxyError> {
+        self.check_at(path, key, value, Instant::now())
+    }
+
+    fn check_at(
+        &self,
+        path: &str,
+        key: RateLimitKey,
+        value: &str,
+        now: Instant,
+    ) -> Result<(), ProxyError> {
+        let matching: Vec<_> = self
+            .rules
+            .iter()
+            .enumerate()
+            .filter(|(_, rule)| rule.matches(path, key))
+            .collect();
+
+        // Refill and check every bucket before debiting any of them
+        let mut retry_after = None;
+        for &(index, rule) in &matching {
+            let capacity = rule.capacity();
+            let refill_per_sec = rule.refill_per_sec();
+
+            let mut bucket = self
+                .buckets
+                .entry((index, value.to_string()))
+                .or_insert_with(|| TokenBucket {
+                    tokens: capacity,
+                    updated_at: now,
+                });
+
+            let elapsed = now.saturating_duration_since(bucket.updated_at);
+            bucket.tokens = (bucket.tokens + elapsed.as_secs_f64() * refill_per_sec).min(capacity);
+            bucket.updated_at = now;
+
+            if bucket.tokens < 1.0 {
+                let wait = if refill_per_sec > 0.0 {
+                    ((1.0 - bucket.tokens) / refill_per_sec).ceil() as u64
+                } else {
+                    60
+                };
+                retry_after = retry_after.max(Some(wait.max(1)));
+            }
+        }
+        if let Some(retry_after) = retry_after {
+            return Err(ProxyError::RateLimited { retry_after });
+        }
+
+        for (index, _) in matching {
+            if let Some(mut bucket) = self.buckets.get_mut(&(index, value.to_string())) {
+                bucket.tokens -= 1.0;
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Drops buckets that have been idle long enough to be full again
+    pub fn cleanup(&self) {
+        let now = Instant::now();
+        self.buckets.retain(|(index, _), bucket| {
+            let rule = &self.rules[*index];
+            let refill = now
+                .saturating_duration_since(bucket.updated_at)
+                .as_secs_f64()
+                * rule.refill_per_sec();
+            bucket.tokens + refill < rule.capacity()
+        });
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        !self.rules.is_empty()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn rule(route: &str, requests_per_minute: u32, burst: u32) -> RateLimitRule {
+        RateLimitRule {
+            route: route.to_string(),
+            requests_per_minute,
+            burst: Some(burst),
+            key: RateLimitKey::Ip,
+        }
+    }
+
+    #[test]
+    fn test_burst_then_limited() {
+        let limiter = RateLimiter::new(vec![rule("/api/alexa/smart_home", 60, 2)]);
+        let now = Instant::now();
+
+        let check =
+            |now| limiter.check_at("/api/alexa/smart_home", RateLimitKey::Ip, "1.2.3.4", now);
+        assert!(check(now).is_ok());
+        assert!(check(now).is_ok());
+        assert!(matches!(
+            check(now),
+            Err(ProxyError::RateLimited { retry_after: 1 })
+        ));
+
+        // One token per second is refilled
+        assert!(check(now + Duration::from_secs(1)).is_ok());
+    }
+
+    #[test]
+    fn test_keys_and_routes_are_independent() {
+        let limiter = RateLimiter::new(vec![rule("/api/alexa/smart_home", 60, 1)]);
+        let now = Instant::now();
+
+        let check = |path, key, value| limiter.check_at(path, key, value, now).is_ok();
+        assert!(check("/api/alexa/smart_home", RateLimitKey::Ip, "1.2.3.4"));
+        assert!(check("/api/alexa/smart_home", RateLimitKey::Ip, "5.6.7.8"));
+        assert!(check("/api/google_assistant", RateLimitKey::Ip, "1.2.3.4"));
+        assert!(check(
+            "/api/alexa/smart_home",
+            RateLimitKey::Client,
+            "1.2.3.4"
+        ));
+        assert!(!check("/api/alexa/smart_home", RateLimitKey::Ip, "1.2.3.4"));
+    }
+
+    #[test]
+    fn test_rejected_requests_take_no_tokens() {
+        let limiter =
+            RateLimiter::new(vec![rule("*", 60, 3), rule("/api/alexa/smart_home", 60, 1)]);
+        let now = Instant::now();
+
+        let check = |path| {
+            limiter
+                .check_at(path, RateLimitKey::Ip, "1.2.3.4", now)
+                .is_ok()
+        };
+        assert!(check("/api/alexa/smart_home"));
+        assert!(!check("/api/alexa/smart_home"));
+        assert!(!check("/api/alexa/smart_home"));
+
+        // The route wide bucket was only charged for the accepted request
+        assert!(check("/api/google_assistant"));
+        assert!(check("/api/google_assistant"));
+        assert!(!check("/api/google_assistant"));
+    }
+}
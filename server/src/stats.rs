@@ -0,0 +1,188 @@
+use serde::Serialize;
+use std::collections::VecDeque;
+use std::sync::Mutex;
+use std::time::Instant;
+
+/// Completed requests kept for the dashboard
+const RECENT_REQUESTS: usize = 200;
+
+/// Minutes of per-minute counters kept for error rates
+const HISTORY_MINUTES: u64 = 60;
+
+/// A request answered by the server
+#[derive(Debug, Clone, Serialize)]
+pub struct RequestRecord {
+    pub timestamp: u64,
+    pub method: String,
+    pub path: String,
+    pub status: u16,
+    pub latency_ms: u64,
+    /// Client that answered the request (None = answered by the server itself)
+    pub client_id: Option<String>,
+}
+
+/// Requests and errors of one minute
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct MinuteCounts {
+    /// Start of the minute as unix timestamp
+    pub minute: u64,
+    pub requests: u64,
+    /// Responses with status 5xx
+    pub errors: u64,
+}
+
+#[derive(Debug, Serialize)]
+pub struct StatsSummary {
+    pub uptime_s: u64,
+    pub total_requests: u64,
+    pub total_errors: u64,
+    /// Share of 5xx responses over the last 5 minutes
+    pub error_rate_5m: f64,
+    /// Share of 5xx responses over the last hour
+    pub error_rate_1h: f64,
+    pub history: Vec<MinuteCounts>,
+}
+
+#[derive(Default)]
+struct Inner {
+    recent: VecDeque<RequestRecord>,
+    history: VecDeque<MinuteCounts>,
+    total_requests: u64,
+    total_errors: u64,
+}
+
+/// History of answered requests, kept in memory for the dashboard
+pub struct RequestStats {
+    started_at: Instant,
+    inner: Mutex<Inner>,
+}
+
+impl RequestStats {
+    pub fn new() -> Self {
+        Self {
+            started_at: Instant::now(),
+            inner: Mutex::new(Inner::default()),
+        }
+    }
+
+    pub fn record(&self, record: RequestRecord) {
+        let is_error = record.status >= 500;
+        let minute = record.timestamp - record.timestamp % 60;
+
+        let mut inner = self.inner.lock().unwrap();
+        inner.total_requests += 1;
+        if is_error {
+            inner.total_errors += 1;
+        }
+
+        if inner.history.back().is_none_or(|last| last.minute < minute) {
+            inner.history.push_back(MinuteCounts {
+                minute,
+                ..Default::default()
+            });
+        }
+        if let Some(current) = inner.history.back_mut() {
+            current.requests += 1;
+            if is_error {
+                current.errors += 1;
+            }
+        }
+        while inner
+            .history
+            .front()
+            .is_some_and(|first| first.minute + HISTORY_MINUTES * 60 <= minute)
+        {
+            inner.history.pop_front();
+        }
+
+        if inner.recent.len() == RECENT_REQUESTS {
+            inner.recent.pop_front();
+        }
+        inner.recent.push_back(record);
+    }
+
+    /// Most recent requests first
+    pub fn recent(&self) -> Vec<RequestRecord> {
+        let inner = self.inner.lock().unwrap();
+        inner.recent.iter().rev().cloned().collect()
+    }
+
+    pub fn summary(&self, now: u64) -> StatsSummary {
+        let inner = self.inner.lock().unwrap();
+        let error_rate = |minutes: u64| {
+            let since = now.saturating_sub(minutes * 60);
+            let (requests, errors) = inner
+                .history
+                .iter()
+                .filter(|counts| counts.minute + 60 > since)
+                .fold((0, 0), |(requests, errors), counts| {
+                    (requests + counts.requests, errors + counts.errors)
+                });
+            if requests == 0 {
+                0.0
+            } else {
+                errors as f64 / requests as f64
+            }
+        };
+
+        StatsSummary {
+            uptime_s: self.started_at.elapsed().as_secs(),
+            total_requests: inner.total_requests,
+            total_errors: inner.total_errors,
+            error_rate_5m: error_rate(5),
+            error_rate_1h: error_rate(HISTORY_MINUTES),
+            history: inner.history.iter().cloned().collect(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn record(timestamp: u64, status: u16) -> RequestRecord {
+        RequestRecord {
+            timestamp,
+            method: "POST".to_string(),
+            path: "/api/alexa".to_string(),
+            status,
+            latency_ms: 10,
+            client_id: None,
+        }
+    }
+
+    #[test]
+    fn test_recent_is_bounded() {
+        let stats = RequestStats::new();
+        for i in 0..RECENT_REQUESTS as u64 + 5 {
+            stats.record(record(i, 200));
+        }
+
+        let recent = stats.recent();
+        assert_eq!(recent.len(), RECENT_REQUESTS);
+        assert_eq!(recent[0].timestamp, RECENT_REQUESTS as u64 + 4);
+    }
+
+    #[test]
+    fn test_error_rates() {
+        let stats = RequestStats::new();
+        let now = 10_000 * 60;
+        // An hour ago, outside both windows
+        stats.record(record(now - 3600, 502));
+        // 30 minutes ago, only in the hourly window
+        stats.record(record(now - 1800, 502));
+        stats.record(record(now - 1800, 200));
+        // Within the last 5 minutes
+        stats.record(record(now - 60, 200));
+        stats.record(record(now - 60, 504));
+        stats.record(record(now, 200));
+        stats.record(record(now, 404));
+
+        let summary = stats.summary(now);
+        assert_eq!(summary.total_requests, 7);
+        assert_eq!(summary.total_errors, 3);
+        assert_eq!(summary.error_rate_5m, 0.25);
+        assert_eq!(summary.error_rate_1h, 2.0 / 6.0);
+        assert_eq!(summary.history.len(), 3);
+    }
+}
@@ -0,0 +1,181 @@
+use common::error::ProxyError;
+use serde_json::Value;
+
+pub const ALEXA_PATH: &str = "/api/alexa/smart_home";
+pub const GOOGLE_PATH: &str = "/api/google_assistant";
+
+/// Smart home intents Google sends to the fulfillment URL
+const GOOGLE_INTENTS: [&str; 4] = [
+    "action.devices.SYNC",
+    "action.devices.QUERY",
+    "action.devices.EXECUTE",
+    "action.devices.DISCONNECT",
+];
+
+/// Validates the structure of an Alexa Smart Home directive
+/// (payload version 3) before it is sent through the tunnel.
+///
+/// Expected format:
+/// `{"directive": {"header": {"namespace", "name", "payloadVersion", "messageId"}, "payload": {...}}}`
+pub fn validate_alexa_request(body: Option<&[u8]>, max_body_size: usize) -> Result<(), ProxyError> {
+    let body = body.ok_or_else(|| invalid("Missing request body"))?;
+    if body.len() > max_body_size {
+        return Err(invalid(format!(
+            "Payload of {} bytes exceeds limit of {} bytes",
+            body.len(),
+            max_body_size
+        )));
+    }
+
+    let json: Value =
+        serde_json::from_slice(body).map_err(|_| invalid("Body is not valid JSON"))?;
+    let directive = json
+        .get("directive")
+        .filter(|d| d.is_object())
+        .ok_or_else(|| invalid("Missing directive"))?;
+    let header = directive
+        .get("header")
+        .filter(|h| h.is_object())
+        .ok_or_else(|| invalid("Missing directive header"))?;
+
+    let namespace = non_empty_str(header, "namespace")?;
+    if !namespace.starts_with("Alexa") {
+        return Err(invalid(format!("Unknown namespace {}", namespace)));
+    }
+    non_empty_str(header, "name")?;
+    non_empty_str(header, "messageId")?;
+    if header.get("payloadVersion").and_then(Value::as_str) != Some("3") {
+        return Err(invalid("Unsupported payloadVersion"));
+    }
+
+    if !directive.get("payload").is_some_and(Value::is_object) {
+        return Err(invalid("Missing directive payload"));
+    }
+
+    // Every directive carries the account linking token, either on the
+    // endpoint (most directives) or in the payload (discovery, grants)
+    let token = [
+        "/endpoint/scope/token",
+        "/payload/scope/token",
+        "/payload/grantee/token",
+    ]
+    .iter()
+    .find_map(|pointer| directive.pointer(pointer).and_then(Value::as_str));
+    if token.is_none_or(str::is_empty) {
+        return Err(invalid("Missing bearer token"));
+    }
+
+    Ok(())
+}
+
+/// Validates a Google Assistant smart home fulfillment request before it is
+/// sent through the tunnel.
+///
+/// Requires an `Authorization: Bearer` header and a body of the form
+/// `{"requestId": "...", "inputs": [{"intent": "action.devices.SYNC", ...}]}`
+pub fn validate_google_request(
+    headers: &[(String, String)],
+    body: Option<&[u8]>,
+    max_body_size: usize,
+) -> Result<(), ProxyError> {
+    let token = headers
+        .iter()
+        .find(|(name, _)| name.eq_ignore_ascii_case("authorization"))
+        .and_then(|(_, value)| value.strip_prefix("Bearer "))
+        .map(str::trim);
+    if token.is_none_or(str::is_empty) {
+        return Err(invalid("Missing bearer token"));
+    }
+
+    let body = body.ok_or_else(|| invalid("Missing request body"))?;
+    if body.len() > max_body_size {
+        return Err(invalid(format!(
+            "Payload of {} bytes exceeds limit of {} bytes",
+            body.len(),
+            max_body_size
+        )));
+    }
+
+    let json: Value =
+        serde_json::from_slice(body).map_err(|_| invalid("Body is not valid JSON"))?;
+    non_empty_str(&json, "requestId")?;
+
+    let inputs = json
+        .get("inputs")
+        .and_then(Value::as_array)
+        .filter(|inputs| !inputs.is_empty())
+        .ok_or_else(|| invalid("Missing inputs"))?;
+    for input in inputs {
+        let intent = non_empty_str(input, "intent")?;
+        if !GOOGLE_INTENTS.contains(&intent) {
+            return Err(invalid(format!("Unsupported intent {}", intent)));
+        }
+    }
+
+    Ok(())
+}
+
+fn non_empty_str<'a>(value: &'a Value, field: &str) -> Result<&'a str, ProxyError> {
+    value
+        .get(field)
+        .and_then(Value::as_str)
+        .filter(|v| !v.is_empty())
+        .ok_or_else(|| invalid(format!("Missing {}", field)))
+}
+
+fn invalid(message: impl Into<String>) -> ProxyError {
+    ProxyError::InvalidRequest(message.into())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const DISCOVERY: &str = r#"{"directive": {
+        "header": {"namespace": "Alexa.Discovery", "name": "Discover", "payloadVersion": "3", "messageId": "abc"},
+        "payload": {"scope": {"type": "BearerToken", "token": "access-token"}}
+    }}"#;
+
+    #[test]
+    fn test_alexa_valid_discovery() {
+        assert!(validate_alexa_request(Some(DISCOVERY.as_bytes()), 65536).is_ok());
+    }
+
+    #[test]
+    fn test_alexa_oversized() {
+        assert!(validate_alexa_request(Some(DISCOVERY.as_bytes()), 16).is_err());
+    }
+
+    #[test]
+    fn test_alexa_missing_token() {
+        let body = DISCOVERY.replace("access-token", "");
+        assert!(validate_alexa_request(Some(body.as_bytes()), 65536).is_err());
+    }
+
+    #[test]
+    fn test_google_valid_sync() {
+        let headers = vec![("authorization".to_string(), "Bearer token".to_string())];
+        let body = br#"{"requestId": "123", "inputs": [{"intent": "action.devices.SYNC"}]}"#;
+        assert!(validate_google_request(&headers, Some(body), 65536).is_ok());
+    }
+
+    #[test]
+    fn test_google_rejects_invalid() {
+        let headers = vec![("authorization".to_string(), "Bearer token".to_string())];
+        let unknown_intent =
+            br#"{"requestId": "123", "inputs": [{"intent": "action.devices.FOO"}]}"#;
+        let no_inputs = br#"{"requestId": "123", "inputs": []}"#;
+        let valid = br#"{"requestId": "123", "inputs": [{"intent": "action.devices.QUERY"}]}"#;
+
+        assert!(validate_google_request(&headers, Some(unknown_intent), 65536).is_err());
+        assert!(validate_google_request(&headers, Some(no_inputs), 65536).is_err());
+        assert!(validate_google_request(&[], Some(valid), 65536).is_err());
+    }
+
+    #[test]
+    fn test_alexa_malformed() {
+        assert!(validate_alexa_request(None, 65536).is_err());
+        assert!(validate_alexa_request(Some(b"not json"), 65536).is_err());
+        assert!(validate_alexa_request(Some(br#"{"directive": {}}"#), 65536).is_err());
+    }
+}